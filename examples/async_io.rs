@@ -0,0 +1,27 @@
+use futures::StreamExt;
+use std::io;
+use tokio_nflog::{AddressFamily, CopyMode, QueueConfig};
+
+async fn run() -> io::Result<()> {
+    let config = QueueConfig {
+        address_families: vec![AddressFamily::Inet, AddressFamily::Inet6],
+        group_num: 10,
+        copy_mode: Some(CopyMode::Packet),
+        range: Some(0xffff),
+        ..Default::default()
+    };
+    let queue = config.build_stream()?;
+
+    println!("Starting nflog streaming");
+
+    let mut stream = queue.async_io_socket()?;
+    while let Some(msg) = stream.next().await {
+        println!("{}", msg?.prefix());
+    }
+
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    async_io::block_on(run())
+}
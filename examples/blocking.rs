@@ -0,0 +1,28 @@
+use std::io;
+use std::time::Duration;
+use tokio_nflog::{AddressFamily, CopyMode, Message, QueueConfig};
+
+fn main() -> io::Result<()> {
+    let config = QueueConfig {
+        address_families: vec![AddressFamily::Inet],
+        group_num: 10,
+        copy_mode: Some(CopyMode::Packet),
+        range: Some(0xffff),
+        ..Default::default()
+    };
+    let handler = |msg: Message<'_>| {
+        println!("{}", msg.prefix());
+        Ok(())
+    };
+    let mut socket = config.build(handler)?.blocking_socket()?;
+
+    println!("Starting nflog listening");
+
+    loop {
+        match socket.recv_timeout(Duration::from_secs(5)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => println!("No messages for 5s"),
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::{env, io};
+use tokio_nflog::{AddressFamily, CopyMode, FanoutClient, FanoutServer, QueueConfig};
+
+const SOCKET_PATH: &str = "/tmp/nflog-10.sock";
+
+async fn serve() -> io::Result<()> {
+    let config = QueueConfig {
+        address_families: vec![AddressFamily::Inet, AddressFamily::Inet6],
+        group_num: 10,
+        copy_mode: Some(CopyMode::Packet),
+        range: Some(0xffff),
+        ..Default::default()
+    };
+    let queue = config.build_stream()?;
+
+    let server = FanoutServer::bind(queue.socket()?, SOCKET_PATH, 1024)?;
+    println!("Serving nflog group 10 on {}", SOCKET_PATH);
+
+    server.run().await
+}
+
+async fn consume() -> io::Result<()> {
+    let mut client = FanoutClient::connect(SOCKET_PATH).await?;
+
+    while let Some(msg) = client.recv().await {
+        let msg = msg?;
+        println!("{} {:?}", msg.prefix(), msg.payload().map(<[u8]>::len));
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    match env::args().nth(1).as_deref() {
+        Some("server") => serve().await,
+        Some("client") => consume().await,
+        _ => {
+            eprintln!("usage: fanout server|client");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use tokio::task::LocalSet;
+use tokio_nflog::{AddressFamily, CopyMode, Message, QueueConfig};
+
+type Counters = Rc<RefCell<HashMap<String, usize>>>;
+
+async fn run(counters: Counters) -> io::Result<()> {
+    let config = QueueConfig {
+        address_families: vec![AddressFamily::Inet],
+        group_num: 10,
+        copy_mode: Some(CopyMode::Meta),
+        ..Default::default()
+    };
+    let handler = move |msg: Message<'_>| {
+        *counters
+            .borrow_mut()
+            .entry(msg.prefix().into_owned())
+            .or_default() += 1;
+        Ok(())
+    };
+
+    let mut socket = config.build(handler)?.socket()?;
+    socket.listen().await
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let counters = Counters::default();
+    let local = LocalSet::new();
+
+    let task = local.spawn_local(run(counters.clone()));
+    local.spawn_local({
+        let counters = counters.clone();
+        async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                println!("{:?}", counters.borrow());
+            }
+        }
+    });
+
+    local.run_until(task).await.unwrap()
+}
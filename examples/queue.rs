@@ -1,14 +1,24 @@
 use std::io;
 use tokio_nflog::{AddressFamily, CopyMode, Flags, Message, MessageHandler, QueueConfig};
 
-struct Handler {}
+struct Handler {
+    count: usize,
+}
 
 impl MessageHandler for Handler {
-    fn handle(&mut self, msg: Message<'_>) {
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
         let packet = NflogPacket {
             prefix: msg.prefix().to_string(),
         };
         println!("Got {:#?}", packet);
+        self.count += 1;
+
+        Ok(())
+    }
+
+    fn on_shutdown(&mut self) -> io::Result<()> {
+        println!("Stopping nflog listening");
+        Ok(())
     }
 }
 
@@ -26,15 +36,23 @@ async fn run() -> io::Result<()> {
         flags: Some(Flags::SEQUENCE),
         ..Default::default()
     };
-    let handler = Handler {};
+    let handler = Handler { count: 0 };
     let queue = config.build(handler)?;
 
     println!("Starting nflog listening");
 
-    let mut socket = queue.socket()?;
-    socket.listen().await?;
+    let socket = queue.socket()?;
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    let (handler, result) = match socket.listen_until(shutdown).await {
+        Ok(handler) => (handler, Ok(())),
+        Err((handler, e)) => (handler, Err(e)),
+    };
+
+    println!("Got {} messages", handler.count);
 
-    Ok(())
+    result
 }
 
 #[tokio::main]
@@ -0,0 +1,67 @@
+//! Compares the receive syscalls, throughput and CPU time of the receive modes.
+//!
+//! Generate traffic matching an nflog rule for group 10 while it runs, i.e.
+//! `iptables -A INPUT -j NFLOG --nflog-group 10` and a flood ping.
+
+use std::io;
+use std::time::{Duration, Instant};
+use tokio_nflog::{CopyMode, Message, MessageHandler, QueueConfig, RecvMode};
+
+const DURATION: Duration = Duration::from_secs(10);
+
+struct Discard;
+
+impl MessageHandler for Discard {
+    fn handle(&mut self, _msg: Message<'_>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn bench(recv_mode: RecvMode) -> io::Result<()> {
+    let config = QueueConfig {
+        group_num: 10,
+        copy_mode: Some(CopyMode::Packet),
+        range: Some(0xffff),
+        recv_mode,
+        ..Default::default()
+    };
+    let mut socket = config.build(Discard)?.socket()?;
+
+    let start = Instant::now();
+    let start_cpu = cpu_time();
+    if let Ok(result) = tokio::time::timeout(DURATION, socket.listen()).await {
+        result?;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let cpu = (cpu_time() - start_cpu).as_secs_f64();
+
+    let stats = socket.stats();
+    println!(
+        "{:?}: {} datagrams ({:.0}/s), {} syscalls ({:.2} datagrams/syscall), \
+         {:.2}s CPU ({:.2} us/datagram)",
+        recv_mode,
+        stats.datagrams,
+        stats.datagrams as f64 / elapsed,
+        stats.syscalls,
+        stats.datagrams as f64 / stats.syscalls.max(1) as f64,
+        cpu,
+        cpu * 1e6 / stats.datagrams.max(1) as f64,
+    );
+
+    Ok(())
+}
+
+/// User and system CPU time of the process.
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+
+    let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    bench(RecvMode::Recv).await?;
+    bench(RecvMode::RecvMmsg { batch: 32 }).await
+}
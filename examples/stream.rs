@@ -0,0 +1,32 @@
+use futures::StreamExt;
+use std::io;
+use tokio_nflog::{AddressFamily, CopyMode, QueueConfig};
+
+async fn run() -> io::Result<()> {
+    let config = QueueConfig {
+        address_families: vec![AddressFamily::Inet, AddressFamily::Inet6],
+        group_num: 10,
+        copy_mode: Some(CopyMode::Packet),
+        range: Some(0xffff),
+        ..Default::default()
+    };
+    let queue = config.build_stream()?;
+
+    println!("Starting nflog streaming");
+
+    let mut stream = queue.socket()?;
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        match msg.flow_key() {
+            Some(Ok(flow)) => println!("{} {}", msg.prefix(), flow.community_id()),
+            _ => println!("{}", msg.prefix()),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    run().await
+}
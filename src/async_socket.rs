@@ -0,0 +1,185 @@
+use async_io::Async;
+use futures::{future, ready, Stream};
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::blocking::recv_nonblocking;
+use super::queue_handle::NflogFd;
+use super::socket::{self, RecvSocket};
+use super::stream;
+use super::{AsyncMessageHandler, MessageBuffer, MessageHandler, OwnedMessage, Queue, RecvStats};
+
+/// Socket driven by the `async-io` reactor, for async-std, smol and other
+/// runtimes built on it.
+///
+/// It has the same API as [QueueSocket](crate::QueueSocket), and
+/// [AsyncIoStream](AsyncIoStream) as [QueueStream](crate::QueueStream),
+/// except for:
+///
+/// * [recv_mode](crate::QueueConfig::recv_mode), which is ignored: one
+///   datagram is read per syscall.
+/// * `spawn_broadcast`, `spawn_into_channel` and
+///   [FanoutServer](crate::FanoutServer), which need a tokio runtime.
+pub struct AsyncIoSocket<H> {
+    // Declared before `queue`, so that the fd is removed from the reactor
+    // before it is closed.
+    io: Async<NflogFd>,
+    queue: Queue<H>,
+    buffer: Box<[u8]>,
+    stats: RecvStats,
+}
+
+/// [QueueStream](crate::QueueStream) driven by the `async-io` reactor.
+pub type AsyncIoStream = AsyncIoSocket<MessageBuffer>;
+
+impl<H> AsyncIoSocket<H>
+where
+    H: MessageHandler,
+{
+    pub(crate) fn new(queue: Queue<H>) -> io::Result<Self> {
+        let io = Async::new(queue.handle.nflog_fd())?;
+        let buffer = vec![0; queue.config.buffer_size].into_boxed_slice();
+
+        Ok(Self {
+            io,
+            queue,
+            buffer,
+            stats: RecvStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> RecvStats {
+        self.stats
+    }
+
+    pub fn handler(&self) -> &H {
+        self.queue.handler()
+    }
+
+    /// Get the handler between calls of [recv](AsyncIoSocket::recv).
+    pub fn handler_mut(&mut self) -> &mut H {
+        self.queue.handler_mut()
+    }
+
+    /// Close the socket and return the handler.
+    pub fn into_handler(self) -> H {
+        self.into_queue().into_handler()
+    }
+
+    /// Stop reading the socket and return the queue.
+    pub fn into_queue(self) -> Queue<H> {
+        let Self { io, queue, .. } = self;
+        // Removed from the reactor while the fd is still open.
+        drop(io);
+        queue
+    }
+
+    /// Receive datagrams and pass their messages to the handler.
+    ///
+    /// Reads datagrams until the socket would block or
+    /// [recv_budget](crate::QueueConfig::recv_budget) datagrams are read.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        socket::poll_recv(self, cx)
+    }
+
+    fn poll_recv_datagram(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let n = loop {
+            self.stats.syscalls += 1;
+            match recv_nonblocking(self.io.as_raw_fd(), &mut self.buffer) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.io.poll_readable(cx))?;
+                }
+                Err(e) => return Poll::Ready(trace_err!(Err(e), "recv")),
+            }
+        };
+        self.stats.datagrams += 1;
+
+        if n > 0 {
+            self.queue.handle_packet(&mut self.buffer[..n])?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    pub async fn recv(&mut self) -> io::Result<()> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub async fn listen(&mut self) -> io::Result<()> {
+        loop {
+            self.recv().await?;
+        }
+    }
+
+    /// Listen until `shutdown` completes and return the handler.
+    ///
+    /// See [QueueSocket::listen_until](crate::QueueSocket::listen_until).
+    pub async fn listen_until<F>(self, shutdown: F) -> Result<H, (H, io::Error)>
+    where
+        F: Future<Output = ()>,
+    {
+        socket::listen_until(self, shutdown).await
+    }
+}
+
+impl<H> RecvSocket for AsyncIoSocket<H>
+where
+    H: MessageHandler,
+{
+    type Handler = H;
+
+    fn queue(&mut self) -> &mut Queue<H> {
+        &mut self.queue
+    }
+
+    fn poll_recv_batch(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        self.poll_recv_datagram(cx).map_ok(|()| 1)
+    }
+
+    fn into_queue(self) -> Queue<H> {
+        AsyncIoSocket::into_queue(self)
+    }
+}
+
+impl<H> AsRawFd for AsyncIoSocket<H> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.queue.as_raw_fd()
+    }
+}
+
+impl<H> AsFd for AsyncIoSocket<H> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.queue.as_fd()
+    }
+}
+
+impl AsyncIoStream {
+    /// See [QueueStream::recv_async](crate::QueueStream::recv_async).
+    pub async fn recv_async<A>(&mut self, handler: &mut A) -> io::Result<()>
+    where
+        A: AsyncMessageHandler,
+    {
+        stream::recv_async(self, handler).await
+    }
+
+    pub async fn listen_async<A>(&mut self, handler: &mut A) -> io::Result<()>
+    where
+        A: AsyncMessageHandler,
+    {
+        loop {
+            self.recv_async(handler).await?;
+        }
+    }
+}
+
+impl Stream for AsyncIoStream {
+    type Item = io::Result<OwnedMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        stream::poll_next_message(self.get_mut(), cx).map(Some)
+    }
+}
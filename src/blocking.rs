@@ -0,0 +1,163 @@
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use super::{MessageHandler, Queue, RecvStats};
+
+/// Socket that blocks the calling thread, for programs without an async
+/// runtime.
+///
+/// Messages are passed to the handler as with
+/// [QueueSocket](crate::QueueSocket).
+pub struct BlockingSocket<H> {
+    queue: Queue<H>,
+    buffer: Box<[u8]>,
+    stats: RecvStats,
+}
+
+impl<H> BlockingSocket<H>
+where
+    H: MessageHandler,
+{
+    pub(crate) fn new(queue: Queue<H>) -> Self {
+        let buffer = vec![0; queue.config.buffer_size].into_boxed_slice();
+
+        Self {
+            queue,
+            buffer,
+            stats: RecvStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> RecvStats {
+        self.stats
+    }
+
+    pub fn handler(&self) -> &H {
+        self.queue.handler()
+    }
+
+    /// Get the handler between calls of [recv](BlockingSocket::recv).
+    pub fn handler_mut(&mut self) -> &mut H {
+        self.queue.handler_mut()
+    }
+
+    /// Close the socket and return the handler.
+    pub fn into_handler(self) -> H {
+        self.queue.into_handler()
+    }
+
+    /// Return the queue, i.e. to change its configuration.
+    pub fn into_queue(self) -> Queue<H> {
+        self.queue
+    }
+
+    /// Wait for a datagram and pass its messages to the handler.
+    pub fn recv(&mut self) -> io::Result<()> {
+        self.recv_until(None)
+    }
+
+    /// Like [recv](BlockingSocket::recv), but fails with a
+    /// [TimedOut](io::ErrorKind::TimedOut) error if no datagram arrives
+    /// within `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn listen(&mut self) -> io::Result<()> {
+        loop {
+            self.recv()?;
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        enter_span!(self.queue.handle);
+        let fd = self.queue.handle.fd();
+
+        let n = loop {
+            wait_readable(fd, deadline)?;
+
+            self.stats.syscalls += 1;
+            match recv_nonblocking(fd, &mut self.buffer) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return trace_err!(Err(e), "recv"),
+            }
+        };
+        self.stats.datagrams += 1;
+
+        if n > 0 {
+            self.queue.handle_packet(&mut self.buffer[..n])?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<H> AsRawFd for BlockingSocket<H> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.queue.as_raw_fd()
+    }
+}
+
+impl<H> AsFd for BlockingSocket<H> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.queue.as_fd()
+    }
+}
+
+/// Read a datagram without blocking, retrying if interrupted.
+pub(crate) fn recv_nonblocking(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let n = unsafe {
+            libc::recv(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Wait until `fd` is readable or `deadline` is reached.
+fn wait_readable(fd: RawFd, deadline: Option<Instant>) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    loop {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                // Round up, so that the deadline is not missed by less than
+                // a millisecond.
+                let millis = left.as_nanos().div_ceil(1_000_000);
+                millis.min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+
+        let rc = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        match rc {
+            0 => return Err(io::ErrorKind::TimedOut.into()),
+            n if n > 0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
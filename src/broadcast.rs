@@ -0,0 +1,333 @@
+use futures::{future, ready};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use super::channel::{channel, register, Sender};
+use super::{MessageReceiver, OverflowPolicy, OwnedMessage, QueueStream};
+
+type Filter = Box<dyn FnMut(&OwnedMessage) -> bool + Send>;
+
+struct Subscriber {
+    sender: Sender,
+    // Only called by the hub task, the mutex makes the subscriber Sync.
+    filter: Option<Mutex<Filter>>,
+}
+
+impl Subscriber {
+    fn matches(&self, msg: &OwnedMessage) -> bool {
+        match &self.filter {
+            Some(filter) => (filter.lock().unwrap_or_else(|e| e.into_inner()))(msg),
+            None => true,
+        }
+    }
+}
+
+struct Shared {
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+    handles: AtomicUsize,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Shared {
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Arc<Subscriber>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn waker(&self) -> MutexGuard<'_, Option<Waker>> {
+        self.waker.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Handle of a socket task that sends each message to all subscribers.
+///
+/// Every subscriber has its own channel. A subscriber that doesn't keep up
+/// loses its oldest messages and receives a [Lagged](crate::Lagged) error
+/// in their place, while the others still get all of them.
+///
+/// The task stops once all handles and subscribers are dropped or the
+/// socket fails with an error other than `ENOBUFS`.
+pub struct Broadcast {
+    shared: Arc<Shared>,
+}
+
+impl Broadcast {
+    /// Subscribe to all messages, keeping at most `capacity` of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn subscribe(&self, capacity: usize) -> MessageReceiver {
+        self.add(capacity, None)
+    }
+
+    /// Subscribe to the messages matching `filter`, keeping at most
+    /// `capacity` of them.
+    ///
+    /// Receive errors of the socket are always passed on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn subscribe_filtered<F>(&self, capacity: usize, filter: F) -> MessageReceiver
+    where
+        F: FnMut(&OwnedMessage) -> bool + Send + 'static,
+    {
+        self.add(capacity, Some(Box::new(filter)))
+    }
+
+    fn add(&self, capacity: usize, filter: Option<Filter>) -> MessageReceiver {
+        let (sender, receiver) = channel(capacity, OverflowPolicy::DropOldest);
+        let filter = filter.map(Mutex::new);
+        self.shared
+            .subscribers()
+            .push(Arc::new(Subscriber { sender, filter }));
+
+        receiver
+    }
+
+    /// Number of subscribers whose receiver is not dropped yet.
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.shared.subscribers();
+        subscribers.retain(|s| !s.sender.is_closed());
+        subscribers.len()
+    }
+
+    /// Create a handle and the future that sends the messages of `source` to
+    /// its subscribers.
+    fn new<S>(mut source: S) -> (Self, impl Future<Output = ()>)
+    where
+        S: FnMut(&mut Context<'_>) -> Poll<io::Result<OwnedMessage>>,
+    {
+        let shared = Arc::new(Shared {
+            subscribers: Mutex::new(Vec::new()),
+            handles: AtomicUsize::new(1),
+            waker: Mutex::new(None),
+        });
+        let hub = shared.clone();
+        let mut targets: Vec<Arc<Subscriber>> = Vec::new();
+
+        let task = future::poll_fn(move |cx| loop {
+            register(&mut hub.waker(), cx);
+
+            {
+                // Registers the task to be woken up once a receiver is dropped.
+                let mut subscribers = hub.subscribers();
+                subscribers.retain(|s| matches!(s.sender.poll_ready(cx), Poll::Ready(Ok(()))));
+
+                if subscribers.is_empty() && hub.handles.load(Ordering::Acquire) == 0 {
+                    return Poll::Ready(());
+                }
+            }
+
+            let item = ready!(source(cx));
+
+            // Filters are called without the lock, so that they can use a
+            // handle of the hub.
+            targets.extend(hub.subscribers().iter().cloned());
+            let stop = match item {
+                Ok(msg) => {
+                    for subscriber in &targets {
+                        if subscriber.matches(&msg) {
+                            subscriber.sender.send(Ok(msg.clone()));
+                        }
+                    }
+                    false
+                }
+                Err(e) => {
+                    for subscriber in &targets {
+                        subscriber.sender.send(Err(copy_error(&e)));
+                    }
+                    // The kernel dropped messages, but the socket is still usable.
+                    e.raw_os_error() != Some(libc::ENOBUFS)
+                }
+            };
+            targets.clear();
+
+            if stop {
+                return Poll::Ready(());
+            }
+        });
+
+        (Self { shared }, task)
+    }
+}
+
+impl Clone for Broadcast {
+    fn clone(&self) -> Self {
+        self.shared.handles.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Broadcast {
+    fn drop(&mut self) {
+        if self.shared.handles.fetch_sub(1, Ordering::AcqRel) == 1 {
+            if let Some(waker) = self.shared.waker().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl QueueStream {
+    /// Move the socket to a new tokio task that broadcasts its messages to
+    /// the subscribers of the returned handle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn spawn_broadcast(mut self) -> Broadcast {
+        let (broadcast, task) = Broadcast::new(move |cx| self.poll_next_message(cx));
+        tokio::spawn(task);
+
+        broadcast
+    }
+}
+
+fn copy_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::tests::{lagged, mark, message, WakeCount};
+    use crate::Lagged;
+    use std::collections::VecDeque;
+    use std::mem;
+
+    type Source = Arc<Mutex<VecDeque<io::Result<OwnedMessage>>>>;
+
+    /// Start a hub reading from a queue of messages, polled by hand.
+    fn hub() -> (Broadcast, Source, impl FnMut() -> Poll<()>) {
+        let source = Source::default();
+        let items = source.clone();
+        let (broadcast, task) =
+            Broadcast::new(
+                move |_: &mut Context<'_>| match items.lock().unwrap().pop_front() {
+                    Some(item) => Poll::Ready(item),
+                    None => Poll::Pending,
+                },
+            );
+
+        let (_, waker) = WakeCount::waker();
+        let mut task = Box::pin(task);
+        let poll = move || task.as_mut().poll(&mut Context::from_waker(&waker));
+        (broadcast, source, poll)
+    }
+
+    fn push(source: &Source, marks: &[u32]) {
+        source
+            .lock()
+            .unwrap()
+            .extend(marks.iter().map(|&m| message(m)));
+    }
+
+    fn drain(receiver: &mut MessageReceiver) -> Vec<u32> {
+        std::iter::from_fn(|| mark(receiver.try_recv())).collect()
+    }
+
+    #[test]
+    fn sends_to_every_subscriber() {
+        let (broadcast, source, mut poll) = hub();
+        let mut all = broadcast.subscribe(8);
+        let mut odd = broadcast.subscribe_filtered(8, |msg| msg.nfmark() % 2 == 1);
+
+        push(&source, &[1, 2, 3]);
+        assert!(poll().is_pending());
+        assert_eq!(drain(&mut all), [1, 2, 3]);
+        assert_eq!(drain(&mut odd), [1, 3]);
+    }
+
+    #[test]
+    fn filters_can_use_the_hub() {
+        let (broadcast, source, mut poll) = hub();
+        let added = Arc::new(Mutex::new(Vec::new()));
+        let mut first = broadcast.subscribe_filtered(8, {
+            let broadcast = broadcast.clone();
+            let added = added.clone();
+            move |_| {
+                added.lock().unwrap().push(broadcast.subscribe(8));
+                broadcast.subscriber_count() > 1
+            }
+        });
+
+        push(&source, &[1, 2]);
+        assert!(poll().is_pending());
+        assert_eq!(drain(&mut first), [1, 2]);
+        assert_eq!(broadcast.subscriber_count(), 3);
+
+        // Subscribers added while a message is sent get the next ones.
+        let mut added = mem::take(&mut *added.lock().unwrap());
+        assert_eq!(drain(&mut added[0]), [2]);
+        assert_eq!(drain(&mut added[1]), []);
+    }
+
+    #[test]
+    fn slow_subscriber_lags() {
+        let (broadcast, source, mut poll) = hub();
+        let mut slow = broadcast.subscribe(2);
+        let mut fast = broadcast.subscribe(8);
+
+        push(&source, &[1, 2, 3, 4, 5]);
+        assert!(poll().is_pending());
+        assert_eq!(drain(&mut fast), [1, 2, 3, 4, 5]);
+        assert_eq!(lagged(slow.try_recv()), Some(Lagged(3)));
+        assert_eq!(drain(&mut slow), [4, 5]);
+        assert_eq!(slow.dropped(), 3);
+    }
+
+    #[test]
+    fn errors_are_sent_to_filtered_subscribers() {
+        let (broadcast, source, mut poll) = hub();
+        let mut none = broadcast.subscribe_filtered(8, |_| false);
+
+        let enobufs = io::Error::from_raw_os_error(libc::ENOBUFS);
+        source.lock().unwrap().push_back(Err(enobufs));
+        push(&source, &[1]);
+        assert!(poll().is_pending());
+        let e = none.try_recv().unwrap().unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOBUFS));
+        assert!(none.try_recv().is_none());
+
+        // Other errors stop the hub.
+        let ebadf = io::Error::from_raw_os_error(libc::EBADF);
+        source.lock().unwrap().push_back(Err(ebadf));
+        assert!(poll().is_ready());
+        let e = none.try_recv().unwrap().unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn subscriber_count_excludes_dropped_receivers() {
+        let (broadcast, _source, _poll) = hub();
+        let first = broadcast.subscribe(1);
+        let _second = broadcast.subscribe(1);
+        assert_eq!(broadcast.subscriber_count(), 2);
+
+        drop(first);
+        assert_eq!(broadcast.subscriber_count(), 1);
+    }
+
+    #[test]
+    fn stops_once_handles_and_subscribers_are_dropped() {
+        let (broadcast, _source, mut poll) = hub();
+        let receiver = broadcast.subscribe(1);
+        let clone = broadcast.clone();
+
+        drop(broadcast);
+        assert!(poll().is_pending());
+        drop(clone);
+        assert!(poll().is_pending());
+        drop(receiver);
+        assert!(poll().is_ready());
+    }
+}
@@ -0,0 +1,471 @@
+use futures::{future, ready, Stream};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::{error, fmt, io, mem};
+
+use super::{OwnedMessage, QueueStream};
+
+/// What to do with a message when the channel is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading the socket until there is space in the channel
+    ///
+    /// The kernel drops messages once the socket buffer is full, see
+    /// [no_enobufs](crate::QueueConfig::no_enobufs).
+    Block,
+    /// Drop the message
+    DropNewest,
+    /// Drop the oldest message of the channel to make space for the message
+    DropOldest,
+}
+
+/// Error received from a [MessageReceiver](MessageReceiver) in place of the
+/// messages that were dropped because the channel was full.
+///
+/// It is returned before the messages that are still in the channel, with
+/// the number of messages dropped since the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl Lagged {
+    /// Returns the lag notice carried by a received error.
+    pub fn from_io_error(e: &io::Error) -> Option<Self> {
+        e.get_ref()?.downcast_ref().copied()
+    }
+}
+
+impl fmt::Display for Lagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "receiver lagged behind, {} messages were dropped",
+            self.0
+        )
+    }
+}
+
+impl error::Error for Lagged {}
+
+type Item = io::Result<OwnedMessage>;
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+}
+
+struct State {
+    items: VecDeque<Item>,
+    // Messages dropped since the last lag notice.
+    lagged: u64,
+    receiver_waker: Option<Waker>,
+    sender_waker: Option<Waker>,
+    receiver_closed: bool,
+    sender_closed: bool,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        // The state is consistent after every operation, so a panic while
+        // the lock was held doesn't matter.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub(crate) fn register(slot: &mut Option<Waker>, cx: &Context<'_>) {
+    match slot {
+        Some(waker) if waker.will_wake(cx.waker()) => {}
+        _ => *slot = Some(cx.waker().clone()),
+    }
+}
+
+pub(crate) fn channel(capacity: usize, policy: OverflowPolicy) -> (Sender, MessageReceiver) {
+    assert!(capacity > 0, "channel capacity must be greater than 0");
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            lagged: 0,
+            receiver_waker: None,
+            sender_waker: None,
+            receiver_closed: false,
+            sender_closed: false,
+        }),
+        capacity,
+        policy,
+        dropped: AtomicU64::new(0),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        MessageReceiver { shared },
+    )
+}
+
+pub(crate) struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    /// Wait until a message can be sent.
+    ///
+    /// Returns an error if the receiver is dropped. The task is woken up
+    /// when that happens even if it waits for something else afterwards.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+        let mut state = self.shared.state();
+        if state.receiver_closed {
+            return Poll::Ready(Err(()));
+        }
+
+        register(&mut state.sender_waker, cx);
+        if self.shared.policy == OverflowPolicy::Block && state.items.len() >= self.shared.capacity
+        {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns `true` if the receiver is dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.shared.state().receiver_closed
+    }
+
+    pub(crate) fn send(&self, item: Item) {
+        let mut state = self.shared.state();
+
+        if state.items.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Block => {}
+                OverflowPolicy::DropNewest => {
+                    state.lagged += 1;
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.lagged += 1;
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        state.items.push_back(item);
+
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        state.sender_closed = true;
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiver of the messages of
+/// [QueueStream::spawn_into_channel](QueueStream::spawn_into_channel) or of
+/// a [Broadcast](crate::Broadcast) subscription.
+///
+/// Receive errors of the socket are passed on as items. If messages were
+/// dropped because the channel was full, a [Lagged](Lagged) error is
+/// received in their place.
+pub struct MessageReceiver {
+    shared: Arc<Shared>,
+}
+
+impl MessageReceiver {
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        let mut state = self.shared.state();
+
+        if let Some(item) = self.pop(&mut state) {
+            return Poll::Ready(Some(item));
+        }
+        if state.sender_closed {
+            return Poll::Ready(None);
+        }
+
+        register(&mut state.receiver_waker, cx);
+        Poll::Pending
+    }
+
+    /// Receive the next message.
+    ///
+    /// Returns `None` once the socket task has stopped, i.e. after a socket
+    /// error or when the runtime is shut down.
+    pub async fn recv(&mut self) -> Option<Item> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Receive a message if one is available.
+    pub fn try_recv(&mut self) -> Option<Item> {
+        let mut state = self.shared.state();
+        self.pop(&mut state)
+    }
+
+    fn pop(&self, state: &mut State) -> Option<Item> {
+        if state.lagged > 0 {
+            let lagged = Lagged(mem::take(&mut state.lagged));
+            return Some(Err(io::Error::other(lagged)));
+        }
+
+        let item = state.items.pop_front()?;
+        // Only a blocked sender waits for space.
+        if self.shared.policy == OverflowPolicy::Block {
+            if let Some(waker) = state.sender_waker.take() {
+                waker.wake();
+            }
+        }
+        Some(item)
+    }
+
+    /// Number of messages waiting in the channel.
+    pub fn len(&self) -> usize {
+        self.shared.state().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.shared.policy
+    }
+
+    /// Number of messages dropped because the channel was full, in total.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MessageReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        state.receiver_closed = true;
+        state.items.clear();
+        if let Some(waker) = state.sender_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Stream for MessageReceiver {
+    type Item = Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl QueueStream {
+    /// Move the socket to a new tokio task that sends its messages to the
+    /// returned receiver.
+    ///
+    /// At most `capacity` messages are kept in the channel, `policy` decides
+    /// what happens to the others. The task stops once the receiver is
+    /// dropped or the socket fails with an error other than `ENOBUFS`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0 or if called outside of a tokio runtime.
+    pub fn spawn_into_channel(
+        mut self,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> MessageReceiver {
+        let (sender, receiver) = channel(capacity, policy);
+
+        tokio::spawn(future::poll_fn(move |cx| loop {
+            if ready!(sender.poll_ready(cx)).is_err() {
+                return Poll::Ready(());
+            }
+
+            let item = ready!(self.poll_next_message(cx));
+            let stop = match &item {
+                Ok(_) => false,
+                // The kernel dropped messages, but the socket is still usable.
+                Err(e) => e.raw_os_error() != Some(libc::ENOBUFS),
+            };
+            sender.send(item);
+            if stop {
+                return Poll::Ready(());
+            }
+        }));
+
+        receiver
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Wake;
+
+    /// Waker that counts how often it was woken.
+    #[derive(Default)]
+    pub(crate) struct WakeCount(AtomicUsize);
+
+    impl WakeCount {
+        pub(crate) fn waker() -> (Arc<Self>, Waker) {
+            let count = Arc::new(Self::default());
+            (count.clone(), Waker::from(count))
+        }
+
+        pub(crate) fn get(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for WakeCount {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn message(nfmark: u32) -> Item {
+        Ok(OwnedMessage::with_nfmark(nfmark))
+    }
+
+    pub(crate) fn mark(item: Option<Item>) -> Option<u32> {
+        item.map(|item| item.unwrap().nfmark())
+    }
+
+    pub(crate) fn lagged(item: Option<Item>) -> Option<Lagged> {
+        Lagged::from_io_error(&item?.unwrap_err())
+    }
+
+    fn fill(sender: &Sender, marks: &[u32]) {
+        for &nfmark in marks {
+            sender.send(message(nfmark));
+        }
+    }
+
+    fn drain(receiver: &mut MessageReceiver) -> Vec<u32> {
+        std::iter::from_fn(|| mark(receiver.try_recv())).collect()
+    }
+
+    #[test]
+    fn block_waits_for_space() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::Block);
+        let (count, waker) = WakeCount::waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(sender.poll_ready(&mut cx), Poll::Ready(Ok(())));
+        fill(&sender, &[1, 2]);
+        assert_eq!(sender.poll_ready(&mut cx), Poll::Pending);
+        assert_eq!(count.get(), 0);
+
+        assert_eq!(mark(receiver.try_recv()), Some(1));
+        assert_eq!(count.get(), 1);
+        assert_eq!(sender.poll_ready(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(receiver.dropped(), 0);
+    }
+
+    #[test]
+    fn drop_newest_at_capacity() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropNewest);
+        let (_, waker) = WakeCount::waker();
+
+        fill(&sender, &[1, 2, 3, 4]);
+        assert_eq!(
+            sender.poll_ready(&mut Context::from_waker(&waker)),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(receiver.len(), 2);
+        assert_eq!(receiver.dropped(), 2);
+        assert_eq!(lagged(receiver.try_recv()), Some(Lagged(2)));
+        assert_eq!(drain(&mut receiver), [1, 2]);
+    }
+
+    #[test]
+    fn drop_oldest_at_capacity() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropOldest);
+
+        fill(&sender, &[1, 2, 3]);
+        assert_eq!(receiver.dropped(), 1);
+        assert_eq!(lagged(receiver.try_recv()), Some(Lagged(1)));
+        assert_eq!(drain(&mut receiver), [2, 3]);
+
+        fill(&sender, &[4, 5, 6, 7]);
+        assert_eq!(receiver.dropped(), 3);
+        assert_eq!(lagged(receiver.try_recv()), Some(Lagged(2)));
+        assert_eq!(drain(&mut receiver), [6, 7]);
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn receiver_is_woken_by_send() {
+        let (sender, mut receiver) = channel(1, OverflowPolicy::Block);
+        let (count, waker) = WakeCount::waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(receiver.poll_recv(&mut cx).is_pending());
+        fill(&sender, &[1]);
+        assert_eq!(count.get(), 1);
+        assert_eq!(mark(ready_item(receiver.poll_recv(&mut cx))), Some(1));
+    }
+
+    #[test]
+    fn receiver_drop_ends_poll_ready() {
+        let (sender, receiver) = channel(1, OverflowPolicy::Block);
+        let (count, waker) = WakeCount::waker();
+        let mut cx = Context::from_waker(&waker);
+
+        fill(&sender, &[1]);
+        assert_eq!(sender.poll_ready(&mut cx), Poll::Pending);
+
+        drop(receiver);
+        assert_eq!(count.get(), 1);
+        assert_eq!(sender.poll_ready(&mut cx), Poll::Ready(Err(())));
+    }
+
+    #[test]
+    fn sender_drop_yields_none_after_the_messages() {
+        let (sender, mut receiver) = channel(4, OverflowPolicy::Block);
+        let (count, waker) = WakeCount::waker();
+        let mut cx = Context::from_waker(&waker);
+
+        fill(&sender, &[1, 2]);
+        drop(sender);
+        assert_eq!(mark(ready_item(receiver.poll_recv(&mut cx))), Some(1));
+        assert_eq!(mark(ready_item(receiver.poll_recv(&mut cx))), Some(2));
+        assert!(ready_item(receiver.poll_recv(&mut cx)).is_none());
+
+        let (sender, mut receiver) = channel(4, OverflowPolicy::Block);
+        assert!(receiver.poll_recv(&mut cx).is_pending());
+        drop(sender);
+        assert_eq!(count.get(), 1);
+        assert!(ready_item(receiver.poll_recv(&mut cx)).is_none());
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        let (sender, mut receiver) = channel(1, OverflowPolicy::Block);
+
+        sender.send(Err(io::Error::from_raw_os_error(libc::ENOBUFS)));
+        let e = receiver.try_recv().unwrap().unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOBUFS));
+    }
+
+    fn ready_item(poll: Poll<Option<Item>>) -> Option<Item> {
+        match poll {
+            Poll::Ready(item) => item,
+            Poll::Pending => panic!("channel is not ready"),
+        }
+    }
+}
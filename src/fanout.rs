@@ -0,0 +1,197 @@
+use bytes::{BufMut, BytesMut};
+use futures::{future, ready, Stream};
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::net::{UnixListener, UnixStream};
+
+use super::{wire, Broadcast, MessageReceiver, OwnedMessage, QueueStream};
+
+// Frames queued for a client are written with one syscall up to this size.
+const WRITE_BATCH: usize = 64 * 1024;
+
+/// Server that owns an nflog group and sends its messages to every client
+/// connected to a local Unix socket.
+///
+/// Only one process can bind a group, this lets several processes consume
+/// it with [FanoutClient](FanoutClient).
+///
+/// # Framing
+///
+/// Every frame starts with its length as a big-endian `u32`, not counting
+/// the length itself, followed by a kind byte:
+///
+/// * `0`: a message. A big-endian `u16` tells which of the optional fields
+///   are present, followed by the fields in the order of
+///   [OwnedMessage](crate::OwnedMessage). Integers are big-endian, byte
+///   strings and the prefix are prefixed with their `u32` length and the
+///   timestamp is encoded as `u64` seconds and `u32` nanoseconds since the
+///   Unix epoch.
+/// * `1`: a receive error of the server, encoded as the `i32` OS error code
+///   (0 if there is none) and the error description.
+/// * `2`: a [Lagged](crate::Lagged) notice, encoded as the `u64` number of
+///   messages dropped for the client.
+///
+/// Fields may only be added at the end of a message, with a new presence
+/// bit if they are optional.
+pub struct FanoutServer {
+    listener: UnixListener,
+    path: PathBuf,
+    broadcast: Broadcast,
+    capacity: usize,
+}
+
+impl FanoutServer {
+    /// Listen on the Unix socket at `path`.
+    ///
+    /// Up to `capacity` messages are queued for each client, a client that
+    /// doesn't keep up loses its oldest messages and receives an error with
+    /// their number in their place. The socket of the stream is moved to a
+    /// new tokio task.
+    ///
+    /// Fails if `path` exists. The socket file is removed when the server is
+    /// dropped, but is left behind if the process is killed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0 or if called outside of a tokio runtime.
+    pub fn bind<P>(stream: QueueStream, path: P, capacity: usize) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        assert!(capacity > 0, "channel capacity must be greater than 0");
+
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path)?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+            broadcast: stream.spawn_broadcast(),
+            capacity,
+        })
+    }
+
+    /// Number of connected clients.
+    ///
+    /// A client is only known to be disconnected after a message fails to
+    /// be sent to it.
+    pub fn client_count(&self) -> usize {
+        self.broadcast.subscriber_count()
+    }
+
+    /// Accept clients until accepting fails.
+    pub async fn run(&self) -> io::Result<()> {
+        loop {
+            let (client, _) = self.listener.accept().await?;
+            let messages = self.broadcast.subscribe(self.capacity);
+
+            tokio::spawn(write_messages(client, messages));
+        }
+    }
+}
+
+impl Drop for FanoutServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn write_messages(mut client: UnixStream, mut messages: MessageReceiver) {
+    let mut buf = BytesMut::new();
+
+    while let Some(item) = messages.recv().await {
+        buf.clear();
+        wire::encode(&item, &mut buf);
+        while buf.len() < WRITE_BATCH {
+            match messages.try_recv() {
+                Some(item) => wire::encode(&item, &mut buf),
+                None => break,
+            }
+        }
+
+        if client.write_all(&buf).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Client of a [FanoutServer](FanoutServer).
+///
+/// Yields the messages and receive errors of the server, and a
+/// [Lagged](crate::Lagged) error when messages were dropped because the
+/// client didn't keep up, like a [MessageReceiver](crate::MessageReceiver).
+pub struct FanoutClient {
+    stream: UnixStream,
+    buffer: BytesMut,
+}
+
+impl FanoutClient {
+    pub async fn connect<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let stream = UnixStream::connect(path).await?;
+
+        Ok(Self {
+            stream,
+            buffer: BytesMut::with_capacity(WRITE_BATCH),
+        })
+    }
+
+    /// Returns `None` once the server closed the connection. Errors of the
+    /// connection itself are returned as `Some(Err(_))` as well, after which
+    /// the client should be dropped.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<OwnedMessage>>> {
+        loop {
+            match wire::decode(&mut self.buffer) {
+                Ok(Some(item)) => return Poll::Ready(Some(item)),
+                Ok(None) => {}
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+
+            if self.buffer.capacity() == self.buffer.len() {
+                self.buffer.reserve(WRITE_BATCH);
+            }
+
+            let n = unsafe {
+                let buf = &mut *(self.buffer.chunk_mut() as *mut _ as *mut [MaybeUninit<u8>]);
+                let mut read = ReadBuf::uninit(buf);
+                let ptr = read.filled().as_ptr();
+
+                if let Err(e) = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read)) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+
+                assert_eq!(ptr, read.filled().as_ptr());
+
+                let n = read.filled().len();
+                self.buffer.advance_mut(n);
+                n
+            };
+
+            if n == 0 {
+                if self.buffer.is_empty() {
+                    return Poll::Ready(None);
+                }
+                self.buffer.clear();
+                return Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into())));
+            }
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<io::Result<OwnedMessage>> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+}
+
+impl Stream for FanoutClient {
+    type Item = io::Result<OwnedMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
@@ -0,0 +1,440 @@
+use std::io;
+
+use super::message::{Message, MessageHandler, OwnedMessage};
+
+impl<F> MessageHandler for F
+where
+    F: FnMut(Message<'_>) -> io::Result<()>,
+{
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        self(msg)
+    }
+}
+
+/// Handler returned by [MessageHandler::filter](MessageHandler::filter).
+#[derive(Debug, Clone)]
+pub struct Filter<H, P> {
+    handler: H,
+    predicate: P,
+}
+
+impl<H, P> Filter<H, P> {
+    pub(crate) fn new(handler: H, predicate: P) -> Self {
+        Self { handler, predicate }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+}
+
+impl<H, P> MessageHandler for Filter<H, P>
+where
+    H: MessageHandler,
+    P: FnMut(&Message<'_>) -> bool,
+{
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        if (self.predicate)(&msg) {
+            self.handler.handle(msg)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn handle_batch_start(&mut self) -> io::Result<()> {
+        self.handler.handle_batch_start()
+    }
+
+    fn handle_batch_end(&mut self) -> io::Result<()> {
+        self.handler.handle_batch_end()
+    }
+
+    fn on_shutdown(&mut self) -> io::Result<()> {
+        self.handler.on_shutdown()
+    }
+}
+
+/// Handler returned by [MessageHandler::tee](MessageHandler::tee).
+#[derive(Debug, Clone)]
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Tee<A, B> {
+    pub(crate) fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A, B> MessageHandler for Tee<A, B>
+where
+    A: MessageHandler,
+    B: MessageHandler,
+{
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        both(self.first.handle(msg), self.second.handle(msg))
+    }
+
+    fn handle_batch_start(&mut self) -> io::Result<()> {
+        both(
+            self.first.handle_batch_start(),
+            self.second.handle_batch_start(),
+        )
+    }
+
+    fn handle_batch_end(&mut self) -> io::Result<()> {
+        both(
+            self.first.handle_batch_end(),
+            self.second.handle_batch_end(),
+        )
+    }
+
+    fn on_shutdown(&mut self) -> io::Result<()> {
+        both(self.first.on_shutdown(), self.second.on_shutdown())
+    }
+}
+
+/// Handler returned by [MessageHandler::chain](MessageHandler::chain).
+#[derive(Debug, Clone)]
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Chain<A, B> {
+    pub(crate) fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A, B> MessageHandler for Chain<A, B>
+where
+    A: MessageHandler,
+    B: MessageHandler,
+{
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        self.first.handle(msg)?;
+        self.second.handle(msg)
+    }
+
+    fn handle_batch_start(&mut self) -> io::Result<()> {
+        self.first.handle_batch_start()?;
+        self.second.handle_batch_start()
+    }
+
+    // Hooks after the last message are called for both handlers, so that
+    // the second one is not left with a half-finished batch.
+    fn handle_batch_end(&mut self) -> io::Result<()> {
+        both(
+            self.first.handle_batch_end(),
+            self.second.handle_batch_end(),
+        )
+    }
+
+    fn on_shutdown(&mut self) -> io::Result<()> {
+        both(self.first.on_shutdown(), self.second.on_shutdown())
+    }
+}
+
+/// Handler returned by [map_owned](map_owned).
+#[derive(Debug, Clone)]
+pub struct MapOwned<F> {
+    f: F,
+}
+
+impl<F> MessageHandler for MapOwned<F>
+where
+    F: FnMut(OwnedMessage) -> io::Result<()>,
+{
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        (self.f)(OwnedMessage::from(msg))
+    }
+}
+
+/// Build a handler that passes a copy of each message to `f`, i.e. to move
+/// it to another task.
+pub fn map_owned<F>(f: F) -> MapOwned<F>
+where
+    F: FnMut(OwnedMessage) -> io::Result<()>,
+{
+    MapOwned { f }
+}
+
+/// Handler that passes each message to all of its handlers in order.
+///
+/// All handlers are called even if some of them fail and the first error is
+/// returned.
+pub struct Dispatcher<H: ?Sized = dyn MessageHandler + Send> {
+    handlers: Vec<Box<H>>,
+}
+
+impl<H: ?Sized> Dispatcher<H> {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, handler: Box<H>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn into_inner(self) -> Vec<Box<H>> {
+        self.handlers
+    }
+}
+
+impl<H: ?Sized> Default for Dispatcher<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: ?Sized> From<Vec<Box<H>>> for Dispatcher<H> {
+    fn from(handlers: Vec<Box<H>>) -> Self {
+        Self { handlers }
+    }
+}
+
+impl<H> MessageHandler for Dispatcher<H>
+where
+    H: MessageHandler + ?Sized,
+{
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        self.dispatch(|handler| handler.handle(msg))
+    }
+
+    fn handle_batch_start(&mut self) -> io::Result<()> {
+        self.dispatch(|handler| handler.handle_batch_start())
+    }
+
+    fn handle_batch_end(&mut self) -> io::Result<()> {
+        self.dispatch(|handler| handler.handle_batch_end())
+    }
+
+    fn on_shutdown(&mut self) -> io::Result<()> {
+        self.dispatch(|handler| handler.on_shutdown())
+    }
+}
+
+impl<H> Dispatcher<H>
+where
+    H: MessageHandler + ?Sized,
+{
+    fn dispatch<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut H) -> io::Result<()>,
+    {
+        let mut result = Ok(());
+        for handler in &mut self.handlers {
+            result = both(result, f(handler));
+        }
+        result
+    }
+}
+
+/// Returns the first error of two results.
+fn both(first: io::Result<()>, second: io::Result<()>) -> io::Result<()> {
+    first.and(second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    /// Handler that records its hook calls and fails them if `fails` is set.
+    struct Recorder {
+        name: &'static str,
+        fails: bool,
+        log: Log,
+    }
+
+    fn recorder(name: &'static str, fails: bool, log: &Log) -> Recorder {
+        Recorder {
+            name,
+            fails,
+            log: log.clone(),
+        }
+    }
+
+    impl Recorder {
+        fn record(&mut self, hook: &str) -> io::Result<()> {
+            self.log
+                .borrow_mut()
+                .push(format!("{} {}", self.name, hook));
+            if self.fails {
+                Err(io::Error::other(self.name))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl MessageHandler for Recorder {
+        fn handle(&mut self, _msg: Message<'_>) -> io::Result<()> {
+            unreachable!("messages can't be built in tests")
+        }
+
+        fn handle_batch_start(&mut self) -> io::Result<()> {
+            self.record("start")
+        }
+
+        fn handle_batch_end(&mut self) -> io::Result<()> {
+            self.record("end")
+        }
+
+        fn on_shutdown(&mut self) -> io::Result<()> {
+            self.record("shutdown")
+        }
+    }
+
+    fn hooks<H: MessageHandler>(handler: &mut H) -> [io::Result<()>; 3] {
+        [
+            handler.handle_batch_start(),
+            handler.handle_batch_end(),
+            handler.on_shutdown(),
+        ]
+    }
+
+    fn error(result: &io::Result<()>) -> String {
+        result.as_ref().unwrap_err().to_string()
+    }
+
+    fn take(log: &Log) -> Vec<String> {
+        log.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn filter_passes_hooks_through() {
+        let log = Log::default();
+        let mut filter = recorder("a", true, &log).filter(|_| false);
+
+        for result in hooks(&mut filter).iter() {
+            assert_eq!(error(result), "a");
+        }
+        assert_eq!(take(&log), ["a start", "a end", "a shutdown"]);
+    }
+
+    #[test]
+    fn tee_calls_both_and_returns_the_first_error() {
+        let log = Log::default();
+        let mut tee = recorder("a", true, &log).tee(recorder("b", true, &log));
+
+        for result in hooks(&mut tee).iter() {
+            assert_eq!(error(result), "a");
+        }
+        assert_eq!(
+            take(&log),
+            [
+                "a start",
+                "b start",
+                "a end",
+                "b end",
+                "a shutdown",
+                "b shutdown"
+            ]
+        );
+
+        let mut tee = recorder("a", false, &log).tee(recorder("b", true, &log));
+        for result in hooks(&mut tee).iter() {
+            assert_eq!(error(result), "b");
+        }
+    }
+
+    #[test]
+    fn chain_stops_at_batch_start_error() {
+        let log = Log::default();
+        let mut chain = recorder("a", true, &log).chain(recorder("b", false, &log));
+
+        assert_eq!(error(&chain.handle_batch_start()), "a");
+        assert_eq!(take(&log), ["a start"]);
+    }
+
+    #[test]
+    fn chain_ends_batch_and_shuts_down_both() {
+        let log = Log::default();
+        let mut chain = recorder("a", true, &log).chain(recorder("b", true, &log));
+
+        assert_eq!(error(&chain.handle_batch_end()), "a");
+        assert_eq!(error(&chain.on_shutdown()), "a");
+        assert_eq!(take(&log), ["a end", "b end", "a shutdown", "b shutdown"]);
+    }
+
+    #[test]
+    fn chain_succeeds_if_both_succeed() {
+        let log = Log::default();
+        let mut chain = recorder("a", false, &log).chain(recorder("b", false, &log));
+
+        for result in hooks(&mut chain).iter() {
+            assert!(result.is_ok());
+        }
+        assert_eq!(
+            take(&log),
+            [
+                "a start",
+                "b start",
+                "a end",
+                "b end",
+                "a shutdown",
+                "b shutdown"
+            ]
+        );
+    }
+
+    #[test]
+    fn dispatcher_calls_every_handler() {
+        let log = Log::default();
+        let mut dispatcher = Dispatcher::<dyn MessageHandler>::from(vec![
+            Box::new(recorder("a", false, &log)) as Box<dyn MessageHandler>,
+            Box::new(recorder("b", true, &log)),
+            Box::new(recorder("c", true, &log)),
+        ]);
+
+        for result in hooks(&mut dispatcher).iter() {
+            assert_eq!(error(result), "b");
+        }
+        assert_eq!(
+            take(&log),
+            [
+                "a start",
+                "b start",
+                "c start",
+                "a end",
+                "b end",
+                "c end",
+                "a shutdown",
+                "b shutdown",
+                "c shutdown"
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_dispatcher_succeeds() {
+        let mut dispatcher = Dispatcher::<dyn MessageHandler>::new();
+        for result in hooks(&mut dispatcher).iter() {
+            assert!(result.is_ok());
+        }
+    }
+}
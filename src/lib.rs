@@ -1,28 +1,64 @@
 #[macro_use]
 mod macros;
 
+#[cfg(feature = "async-io")]
+mod async_socket;
+mod blocking;
+mod broadcast;
+mod channel;
+mod fanout;
+mod handler;
 mod message;
+mod mmsg;
+mod packet;
 mod queue_handle;
+mod socket;
+mod stream;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod wire;
 
 use bitflags::bitflags;
-use bytes::{BufMut, BytesMut};
 use futures::{future, ready};
 use nflog_sys::*;
-use std::os::unix::net::UnixDatagram;
-use std::os::unix::prelude::FromRawFd;
-use std::ptr::NonNull;
+use std::any::Any;
+use std::future::Future;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
 use std::task::{Context, Poll};
-use std::{io, mem::MaybeUninit};
-use tokio::io::ReadBuf;
-use tokio::net::UnixDatagram as TokioUnixDatagram;
-
-use queue_handle::QueueHandle;
-
-pub use message::{L3Protocol, Message, MessageHandler};
+use tokio::io::unix::AsyncFd;
+
+use blocking::recv_nonblocking;
+use mmsg::MmsgBuffers;
+use queue_handle::{Bound, NflogFd, QueueHandle};
+use socket::RecvSocket;
+#[cfg(feature = "io-uring")]
+use uring::UringRecv;
+
+#[cfg(feature = "async-io")]
+pub use async_socket::{AsyncIoSocket, AsyncIoStream};
+pub use blocking::BlockingSocket;
+pub use broadcast::Broadcast;
+pub use channel::{Lagged, MessageReceiver, OverflowPolicy};
+pub use fanout::{FanoutClient, FanoutServer};
+pub use handler::{map_owned, Chain, Dispatcher, Filter, MapOwned, Tee};
+pub use message::{
+    AsyncMessageHandler, HandlerFuture, L3Protocol, LocalHandlerFuture, Message, MessageHandler,
+    OwnedMessage,
+};
 pub use nix::sys::socket::AddressFamily;
+pub use packet::{
+    Decapsulated, DecodeError, FlowKey, IcmpError, InnerPacket, IpHeader, Ipv6ExtHeader,
+    Ipv6Fragment, Ipv6HeaderChain, TunnelKind, TunnelLayer,
+};
 pub use pnet_base::MacAddr;
+pub use stream::{MessageBuffer, QueueStream};
 
 const NFLOG_BUF_SIZE: usize = 150000;
+const NFLOG_RECV_BUDGET: usize = 64;
 
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -35,6 +71,34 @@ pub enum CopyMode {
     Packet = NFULNL_COPY_PACKET,
 }
 
+/// What to do with the rest of a datagram when the handler returns an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Skip the remaining messages of the datagram
+    Stop,
+    /// Handle the remaining messages of the datagram
+    Continue,
+}
+
+/// How datagrams are read from the socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RecvMode {
+    /// One `recv` syscall per datagram
+    Recv,
+    /// Up to `batch` datagrams per `recvmmsg` syscall, each into its own
+    /// buffer of [buffer_size](QueueConfig::buffer_size) bytes
+    RecvMmsg { batch: usize },
+    /// `receives` multishot receives kept outstanding with io_uring, which
+    /// read into `buffers` buffers of [buffer_size](QueueConfig::buffer_size)
+    /// bytes
+    ///
+    /// Falls back to [Recv](RecvMode::Recv) if the kernel does not support
+    /// multishot receives (Linux 6.0) or io_uring is disabled.
+    #[cfg(feature = "io-uring")]
+    IoUring { receives: usize, buffers: u16 },
+}
+
 bitflags! {
     /// Configuration Flags
     pub struct Flags: u16 {
@@ -47,12 +111,19 @@ pub struct QueueConfig {
     pub address_families: Vec<AddressFamily>,
     pub group_num: u16,
     pub buffer_size: usize,
+    /// Maximum number of datagrams read by one `recv` while the socket is
+    /// readable.
+    pub recv_budget: usize,
+    pub recv_mode: RecvMode,
     pub unbind: bool,
 
     pub copy_mode: Option<CopyMode>,
     pub range: Option<u32>,
     pub flags: Option<Flags>,
     pub no_enobufs: Option<bool>,
+    /// What to do when the handler fails, the first error is returned from
+    /// [QueueSocket::recv](QueueSocket::recv) in any case.
+    pub error_policy: ErrorPolicy,
 }
 
 impl Default for QueueConfig {
@@ -61,12 +132,15 @@ impl Default for QueueConfig {
             address_families: vec![AddressFamily::Inet, AddressFamily::Inet6],
             group_num: 0,
             buffer_size: NFLOG_BUF_SIZE,
+            recv_budget: NFLOG_RECV_BUDGET,
+            recv_mode: RecvMode::Recv,
             unbind: false,
 
             copy_mode: None,
             range: None,
             flags: None,
             no_enobufs: None,
+            error_policy: ErrorPolicy::Stop,
         }
     }
 }
@@ -78,19 +152,53 @@ impl QueueConfig {
     {
         Queue::create(self, handler)
     }
+
+    /// Build a queue whose socket is a [QueueStream](QueueStream).
+    pub fn build_stream(self) -> io::Result<Queue<MessageBuffer>> {
+        Queue::create(self, MessageBuffer::default())
+    }
 }
 
+/// Queue bound to an nflog group, passing its messages to a handler of type
+/// `H` once it is turned into a socket.
+///
+/// The queue and its sockets are [Send] and [Sync] only if the handler is:
+///
+/// ```
+/// fn assert_send_sync<T: Send + Sync>() {}
+/// assert_send_sync::<tokio_nflog::Queue<tokio_nflog::MessageBuffer>>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<tokio_nflog::Queue<std::rc::Rc<()>>>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<tokio_nflog::Queue<std::cell::Cell<u32>>>();
+/// ```
 pub struct Queue<H> {
-    handle: QueueHandle,
-    handler: NonNull<H>,
+    // Dropped by hand, so that it is closed before the state is freed.
+    handle: ManuallyDrop<QueueHandle<Bound>>,
+    state: NonNull<HandlerState<H>>,
     config: QueueConfig,
 }
 
-// Handler is only used in callback, but not in Queue/Socket itself.
-// So it's safe to share pointer to handler with callback.
-// TODO:
-// restrict access to handler inside Queue/Socket at type level
-unsafe impl<H> Send for Queue<H> {}
+/// Data shared with callback.
+struct HandlerState<H> {
+    handler: H,
+    policy: ErrorPolicy,
+    error: Option<io::Error>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+// Handler is only used by callback while the queue is mutably borrowed by
+// `handle_packet`, so it's safe to share pointer to handler with callback.
+// The handles of libnetfilter_log are not tied to a thread, and through a
+// shared reference only the handler and the config can be accessed.
+unsafe impl<H: Send> Send for Queue<H> {}
+unsafe impl<H: Sync> Sync for Queue<H> {}
 
 impl<H> Queue<H>
 where
@@ -110,14 +218,20 @@ where
             handle.set_no_enobufs(no_enobufs)?;
         }
 
-        handle.bind_group(config.group_num)?;
+        let handle = handle.bind_group(config.group_num)?;
 
-        let handler = unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(handler))) };
+        let state = Box::new(HandlerState {
+            handler,
+            policy: config.error_policy,
+            error: None,
+            panic: None,
+        });
+        let state = unsafe { NonNull::new_unchecked(Box::into_raw(state)) };
 
         let mut queue = Self {
-            handle,
+            handle: ManuallyDrop::new(handle),
             config,
-            handler,
+            state,
         };
 
         if let (Some(mode), Some(range)) = (queue.config.copy_mode, queue.config.range) {
@@ -154,69 +268,291 @@ where
         QueueSocket::new(self)
     }
 
+    /// Get a socket that blocks the calling thread instead of a tokio one.
+    pub fn blocking_socket(self) -> io::Result<BlockingSocket<H>> {
+        self.register_callback()?;
+        Ok(BlockingSocket::new(self))
+    }
+
+    /// Get a socket driven by the `async-io` reactor instead of a tokio one.
+    #[cfg(feature = "async-io")]
+    pub fn async_io_socket(self) -> io::Result<AsyncIoSocket<H>> {
+        self.register_callback()?;
+        AsyncIoSocket::new(self)
+    }
+
     fn register_callback(&self) -> io::Result<()> {
-        let group_handle = self.handle.group_handle()?;
-        let handler = self.handler.as_ptr();
+        let group_handle = self.handle.group_handle();
+        let state = self.state.as_ptr();
 
         unsafe {
-            nflog_callback_register(
-                group_handle.as_ptr(),
-                Some(callback::<H>),
-                handler as *mut _,
-            )
+            nflog_callback_register(group_handle.as_ptr(), Some(callback::<H>), state as *mut _)
         };
 
         Ok(())
     }
 }
 
+impl<H> Queue<H> {
+    pub fn handler(&self) -> &H {
+        // Callback can't run while the queue is borrowed.
+        unsafe { &self.state.as_ref().handler }
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.state_mut().handler
+    }
+
+    /// Close the queue and return the handler.
+    ///
+    /// Failures to unbind the group or close the handle are ignored, use
+    /// [close](Queue::close) to get them.
+    pub fn into_handler(self) -> H {
+        let mut queue = ManuallyDrop::new(self);
+        // The handle is closed first, so callback can't be called anymore
+        // once the state is freed.
+        unsafe {
+            ManuallyDrop::drop(&mut queue.handle);
+            ptr::drop_in_place(&mut queue.config);
+            Box::from_raw(queue.state.as_ptr()).handler
+        }
+    }
+
+    /// Unbind the group, close the queue and return the handler.
+    ///
+    /// Unlike [into_handler](Queue::into_handler), fails if unbinding the
+    /// group or closing the handle fails. The handler is returned with the
+    /// error, the queue is closed in both cases.
+    pub fn close(self) -> Result<H, (H, io::Error)> {
+        let mut queue = ManuallyDrop::new(self);
+        unsafe {
+            // The handle is closed before the state is freed, even if
+            // unbinding fails.
+            let result = ManuallyDrop::take(&mut queue.handle).close();
+            ptr::drop_in_place(&mut queue.config);
+            let handler = Box::from_raw(queue.state.as_ptr()).handler;
+
+            match result {
+                Ok(()) => Ok(handler),
+                Err(e) => Err((handler, e)),
+            }
+        }
+    }
+
+    fn state_mut(&mut self) -> &mut HandlerState<H> {
+        // State is only borrowed by callback while the queue is mutably
+        // borrowed by `nflog_handle_packet`.
+        unsafe { self.state.as_mut() }
+    }
+
+    /// Pass the messages of a datagram to the handler.
+    ///
+    /// A handler panic is resumed here, once it has unwound out of callback.
+    fn handle_packet(&mut self, buf: &mut [u8]) -> io::Result<()>
+    where
+        H: MessageHandler,
+    {
+        enter_span!(self.handle);
+        trace_err!(self.handler_mut().handle_batch_start(), "batch start")?;
+
+        unsafe {
+            nflog_handle_packet(
+                self.handle.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len() as libc::c_int,
+            );
+        };
+
+        let state = self.state_mut();
+        let error = state.error.take();
+        if let Some(panic) = state.panic.take() {
+            trace!(error, "handler panicked");
+            panic::resume_unwind(panic);
+        }
+        let end = trace_err!(state.handler.handle_batch_end(), "batch end");
+
+        match error {
+            Some(e) => Err(e),
+            None => end,
+        }
+    }
+}
+
+/// Counters of the receive path of [QueueSocket](QueueSocket).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecvStats {
+    /// Number of receive syscalls, including the ones that found no
+    /// datagram. In io_uring mode, the number of `io_uring_enter` syscalls.
+    pub syscalls: u64,
+    /// Number of datagrams read.
+    pub datagrams: u64,
+}
+
+/// Socket that reads datagrams with tokio and passes their messages to the
+/// handler.
+///
+/// It is [Send] if the handler is, so it can be moved to
+/// [tokio::spawn](tokio::spawn):
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<tokio_nflog::QueueSocket<std::rc::Rc<()>>>();
+/// ```
+///
+/// Otherwise, i.e. if the handler holds an `Rc`, it is run on the thread
+/// that created it, by `tokio::task::spawn_local` on a `LocalSet`.
 pub struct QueueSocket<H> {
-    socket: TokioUnixDatagram,
+    // Declared before `queue`, so that the fd is removed from the reactor
+    // and the receives are cancelled before it is closed. This order is
+    // checked by `closing_handles_leaves_other_fds_open`.
+    socket: AsyncFd<NflogFd>,
+    #[cfg(feature = "io-uring")]
+    uring: Option<UringRecv>,
     queue: Queue<H>,
-    buffer: BytesMut,
+    buffer: Box<[u8]>,
+    mmsg: Option<MmsgBuffers>,
+    stats: RecvStats,
 }
 
-impl<H> QueueSocket<H> {
+impl<H> QueueSocket<H>
+where
+    H: MessageHandler,
+{
     fn new(queue: Queue<H>) -> io::Result<Self> {
-        let fd = queue.handle.fd();
-        let socket = unsafe { UnixDatagram::from_raw_fd(fd) };
-        let socket = TokioUnixDatagram::from_std(socket)?;
+        let socket = AsyncFd::new(queue.handle.nflog_fd())?;
 
-        let buffer = BytesMut::with_capacity(queue.config.buffer_size);
+        let buffer = vec![0; queue.config.buffer_size].into_boxed_slice();
+        let mmsg = match queue.config.recv_mode {
+            RecvMode::RecvMmsg { batch } => Some(MmsgBuffers::new(batch, queue.config.buffer_size)),
+            _ => None,
+        };
+        #[cfg(feature = "io-uring")]
+        let mut queue = queue;
+        #[cfg(feature = "io-uring")]
+        let uring = match queue.config.recv_mode {
+            RecvMode::IoUring { receives, buffers } => {
+                let fd = queue.handle.fd();
+                match UringRecv::new(fd, receives, buffers, queue.config.buffer_size) {
+                    Ok(uring) => Some(uring),
+                    Err(_) => {
+                        queue.config.recv_mode = RecvMode::Recv;
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
 
         Ok(Self {
             socket,
             queue,
             buffer,
+            mmsg,
+            #[cfg(feature = "io-uring")]
+            uring,
+            stats: RecvStats::default(),
         })
     }
 
+    pub fn stats(&self) -> RecvStats {
+        self.stats
+    }
+
+    pub fn handler(&self) -> &H {
+        self.queue.handler()
+    }
+
+    /// Get the handler between calls of [recv](QueueSocket::recv).
+    pub fn handler_mut(&mut self) -> &mut H {
+        self.queue.handler_mut()
+    }
+
+    /// Close the socket and return the handler.
+    pub fn into_handler(self) -> H {
+        self.into_queue().into_handler()
+    }
+
+    /// Stop reading the socket and return the queue, i.e. to change its
+    /// configuration before getting a new socket.
+    pub fn into_queue(self) -> Queue<H> {
+        let Self { socket, queue, .. } = self;
+        // Removed from the reactor while the fd is still open.
+        drop(socket);
+        queue
+    }
+
+    /// Receive datagrams and pass their messages to the handler.
+    ///
+    /// Reads datagrams until the socket would block or
+    /// [recv_budget](QueueConfig::recv_budget) datagrams are read. Reading
+    /// also stops early when the task runs out of its tokio budget, so other
+    /// tasks are not starved at high message rates.
     pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.buffer.clear();
+        socket::poll_recv(self, cx)
+    }
+
+    fn poll_recv_mmsg(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mmsg = self.mmsg.as_mut().unwrap();
+        let stats = &mut self.stats;
+
+        let n = loop {
+            let mut guard = ready!(self.socket.poll_read_ready(cx))?;
+
+            match guard.try_io(|socket| {
+                stats.syscalls += 1;
+                mmsg.recv(socket.as_raw_fd())
+            }) {
+                Ok(result) => break trace_err!(result, "recv")?,
+                Err(_would_block) => continue,
+            }
+        };
+        self.stats.datagrams += n as u64;
+
+        // The datagrams are already read, so all of them are handled before
+        // the first error is returned.
+        let mut result = Ok(());
+        for i in 0..n {
+            let handled = self.queue.handle_packet(mmsg.datagram(i));
+            if result.is_ok() {
+                result = handled;
+            }
+        }
+
+        Poll::Ready(result.map(|()| n))
+    }
+
+    #[cfg(feature = "io-uring")]
+    fn poll_recv_uring(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let uring = self.uring.as_mut().unwrap();
+        let queue = &mut self.queue;
 
-        let n = unsafe {
-            let buf = &mut *(self.buffer.chunk_mut() as *mut _ as *mut [MaybeUninit<u8>]);
-            let mut read = ReadBuf::uninit(buf);
-            let ptr = read.filled().as_ptr();
+        let poll = uring.poll_recv(cx, |buf| queue.handle_packet(buf));
+        self.stats.syscalls += uring.take_syscalls();
+        let n = ready!(poll)?;
+        self.stats.datagrams += n as u64;
 
-            ready!(self.socket.poll_recv(cx, &mut read))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_recv_datagram(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let buffer = &mut self.buffer;
+        let stats = &mut self.stats;
 
-            assert_eq!(ptr, read.filled().as_ptr());
+        let n = loop {
+            let mut guard = ready!(self.socket.poll_read_ready(cx))?;
 
-            let n = read.filled().len();
-            self.buffer.advance_mut(n);
-            n
+            match guard.try_io(|socket| {
+                stats.syscalls += 1;
+                recv_nonblocking(socket.as_raw_fd(), buffer)
+            }) {
+                Ok(result) => break trace_err!(result, "recv")?,
+                Err(_would_block) => continue,
+            }
         };
+        self.stats.datagrams += 1;
 
         if n > 0 {
-            let buf = self.buffer.as_ptr();
-            unsafe {
-                nflog_handle_packet(
-                    self.queue.handle.as_ptr(),
-                    buf as *mut libc::c_char,
-                    n as libc::c_int,
-                );
-            };
+            self.queue.handle_packet(&mut self.buffer[..n])?;
         }
 
         Poll::Ready(Ok(()))
@@ -231,11 +567,94 @@ impl<H> QueueSocket<H> {
             self.recv().await?;
         }
     }
+
+    /// Listen until `shutdown` completes and return the handler.
+    ///
+    /// A datagram is always handled as a whole: `shutdown` is only checked
+    /// between calls of [recv](QueueSocket::recv). Once it completes, the
+    /// handler's [on_shutdown](MessageHandler::on_shutdown) is called and the
+    /// group is unbound. Any future can be used, i.e. a `ctrl_c` signal or
+    /// `CancellationToken::cancelled`.
+    ///
+    /// If receiving, `on_shutdown` or [close](Queue::close) fails, the handler
+    /// is returned with the error, so that its state can still be read or
+    /// `on_shutdown` retried. The queue is closed in all cases.
+    pub async fn listen_until<F>(self, shutdown: F) -> Result<H, (H, io::Error)>
+    where
+        F: Future<Output = ()>,
+    {
+        socket::listen_until(self, shutdown).await
+    }
+}
+
+impl<H> RecvSocket for QueueSocket<H>
+where
+    H: MessageHandler,
+{
+    type Handler = H;
+
+    fn queue(&mut self) -> &mut Queue<H> {
+        &mut self.queue
+    }
+
+    fn poll_recv_batch(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        #[cfg(feature = "io-uring")]
+        if self.uring.is_some() {
+            match self.poll_recv_uring(cx) {
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Unsupported => {
+                    trace!(info, error = %e, "io_uring unsupported, falling back to plain recv");
+                    self.uring = None;
+                    self.queue.config.recv_mode = RecvMode::Recv;
+                }
+                poll => return poll,
+            }
+        }
+
+        if self.mmsg.is_some() {
+            self.poll_recv_mmsg(cx)
+        } else {
+            self.poll_recv_datagram(cx).map_ok(|()| 1)
+        }
+    }
+
+    fn into_queue(self) -> Queue<H> {
+        QueueSocket::into_queue(self)
+    }
+}
+
+impl<H> AsRawFd for Queue<H> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.as_raw_fd()
+    }
+}
+
+impl<H> AsFd for Queue<H> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.handle.as_fd()
+    }
+}
+
+impl<H> AsRawFd for QueueSocket<H> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.queue.as_raw_fd()
+    }
+}
+
+impl<H> AsFd for QueueSocket<H> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.queue.as_fd()
+    }
 }
 
 impl<H> Drop for Queue<H> {
     fn drop(&mut self) {
-        let _ = unsafe { Box::from_raw(self.handler.as_ptr()) };
+        // Unbinding the group may pass the messages still queued on the
+        // socket to callback, so the state is freed only after the handle is
+        // closed.
+        unsafe {
+            ManuallyDrop::drop(&mut self.handle);
+            drop(Box::from_raw(self.state.as_ptr()));
+        }
     }
 }
 
@@ -249,25 +668,36 @@ extern "C" fn callback<H: MessageHandler>(
         return 1;
     }
 
-    let result = std::panic::catch_unwind(|| {
-        if nfmsg.is_null() {
-            panic!("nullable nfgenmsg");
-        }
+    let state = unsafe { &mut *(data as *mut HandlerState<H>) };
+    if state.panic.is_some() || (state.error.is_some() && state.policy == ErrorPolicy::Stop) {
+        return 1;
+    }
+    if nfmsg.is_null() {
+        state
+            .error
+            .get_or_insert_with(|| io::Error::new(io::ErrorKind::InvalidData, "nullable nfgenmsg"));
+        return 1;
+    }
 
+    // Unwinding across the C frames of libnetfilter_log is undefined behavior,
+    // so the panic is caught here and resumed by `Queue::handle_packet`.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
         let nfgenmsg = unsafe { &mut *nfmsg };
-        let msg = match Message::new(nfgenmsg.nfgen_family, nfd) {
-            Ok(msg) => msg,
-            Err(e) => panic!("{}", e),
-        };
-        let mut handler = unsafe { Box::from_raw(data as *mut H) };
+        let msg = Message::new(nfgenmsg.nfgen_family, nfd)?;
 
-        handler.as_mut().handle(msg);
-
-        Box::leak(handler);
-    });
+        state.handler.handle(msg)
+    }));
 
     match result {
-        Ok(_) => 0,
-        Err(_) => 1,
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            trace!(warn, error = %e, "message handling failed");
+            state.error.get_or_insert(e);
+            1
+        }
+        Err(panic) => {
+            state.panic = Some(panic);
+            1
+        }
     }
 }
@@ -8,3 +8,44 @@ macro_rules! wrap_io_result {
         }
     }};
 }
+
+/// Emit a `tracing` event if the `tracing` feature is enabled.
+macro_rules! trace {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    };
+}
+
+/// Enter the span of a queue handle until the end of the block.
+macro_rules! enter_span {
+    ($handle:expr) => {
+        #[cfg(feature = "tracing")]
+        let _span = $handle.span().clone().entered();
+    };
+}
+
+/// Emit an event for the result of an operation and return the result.
+macro_rules! traced {
+    ($result:expr, $action:literal $(, $($field:tt)+)?) => {{
+        let result = $result;
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(_) => tracing::debug!($($($field)+,)? concat!($action, " succeeded")),
+            Err(e) => tracing::warn!($($($field)+,)? error = %e, concat!($action, " failed")),
+        }
+        result
+    }};
+}
+
+/// Emit an event if an operation failed and return the result.
+macro_rules! trace_err {
+    ($result:expr, $action:literal) => {{
+        let result = $result;
+        #[cfg(feature = "tracing")]
+        if let Err(e) = &result {
+            tracing::warn!(error = %e, concat!($action, " failed"));
+        }
+        result
+    }};
+}
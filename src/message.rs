@@ -1,22 +1,143 @@
+use bytes::Bytes;
 use nflog_sys::*;
 
 use std::borrow::Cow;
 use std::ffi::CStr;
+use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
+use std::pin::Pin;
 use std::ptr::NonNull;
 use std::slice;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use super::handler::{Chain, Filter, Tee};
+use super::packet::{
+    self, Decapsulated, DecodeError, FlowKey, IcmpError, IpHeader, Ipv6HeaderChain,
+};
 use super::{AddressFamily, MacAddr};
 
 pub trait MessageHandler {
-    fn handle(&mut self, msg: Message<'_>);
+    /// Handle a message.
+    ///
+    /// An error is returned from [QueueSocket::recv](crate::QueueSocket::recv)
+    /// after the messages of the datagram are processed according to
+    /// [ErrorPolicy](crate::ErrorPolicy). A panic skips the remaining
+    /// messages and is resumed by `recv`.
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()>;
+
+    /// Called before the messages of a netlink datagram are handled.
+    ///
+    /// If it fails, the datagram is dropped and the error is returned from
+    /// `recv`.
+    fn handle_batch_start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called after the messages of a netlink datagram are handled, even if
+    /// `handle` failed for some of them.
+    fn handle_batch_end(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once by [QueueSocket::listen_until](crate::QueueSocket::listen_until)
+    /// after the last datagram is handled, i.e. to flush buffered output.
+    fn on_shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Pass only the messages matching `predicate` to this handler.
+    fn filter<P>(self, predicate: P) -> Filter<Self, P>
+    where
+        Self: Sized,
+        P: FnMut(&Message<'_>) -> bool,
+    {
+        Filter::new(self, predicate)
+    }
+
+    /// Pass each message to this handler and to `other`.
+    ///
+    /// Both handlers are called even if one of them fails and the first
+    /// error is returned.
+    fn tee<H>(self, other: H) -> Tee<Self, H>
+    where
+        Self: Sized,
+        H: MessageHandler,
+    {
+        Tee::new(self, other)
+    }
+
+    /// Pass each message to this handler and then, if it succeeded, to
+    /// `next`.
+    fn chain<H>(self, next: H) -> Chain<Self, H>
+    where
+        Self: Sized,
+        H: MessageHandler,
+    {
+        Chain::new(self, next)
+    }
+}
+
+/// Boxed future of an [AsyncMessageHandler](AsyncMessageHandler).
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// Boxed future of an [AsyncMessageHandler](AsyncMessageHandler) that is not
+/// [Send], i.e. because it holds an `Rc`.
+pub type LocalHandlerFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + 'a>>;
+
+/// Handler for [QueueStream::listen_async](crate::QueueStream::listen_async).
+///
+/// The returned future is awaited before the next message is handled and the
+/// socket is read again, so a slow handler makes the kernel queue fill up
+/// instead of buffering messages in memory. Only the datagrams of one read
+/// are buffered, see [recv_async](crate::QueueStream::recv_async).
+///
+/// The future of `listen_async` is [Send] if the handler and its future
+/// are. A handler with a [LocalHandlerFuture](LocalHandlerFuture) is run on
+/// a `LocalSet` instead:
+///
+/// ```no_run
+/// use std::{cell::Cell, io, rc::Rc};
+/// use tokio::task::LocalSet;
+/// use tokio_nflog::{AsyncMessageHandler, LocalHandlerFuture, OwnedMessage, QueueConfig};
+///
+/// struct Count(Rc<Cell<usize>>);
+///
+/// impl AsyncMessageHandler for Count {
+///     type Future<'a> = LocalHandlerFuture<'a>;
+///
+///     fn handle(&mut self, _msg: OwnedMessage) -> Self::Future<'_> {
+///         Box::pin(async move {
+///             self.0.set(self.0.get() + 1);
+///             Ok(())
+///         })
+///     }
+/// }
+///
+/// async fn run(count: Rc<Cell<usize>>) -> io::Result<()> {
+///     let mut stream = QueueConfig::default().build_stream()?.socket()?;
+///     stream.listen_async(&mut Count(count)).await
+/// }
+///
+/// # async fn example() -> io::Result<()> {
+/// let local = LocalSet::new();
+/// local.spawn_local(run(Rc::default())).await??;
+/// # Ok(())
+/// # }
+/// ```
+pub trait AsyncMessageHandler {
+    /// Usually [HandlerFuture](HandlerFuture) or
+    /// [LocalHandlerFuture](LocalHandlerFuture).
+    type Future<'a>: Future<Output = io::Result<()>> + 'a
+    where
+        Self: 'a;
+
+    fn handle(&mut self, msg: OwnedMessage) -> Self::Future<'_>;
 }
 
 pub type L3Protocol = u16;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Message<'a> {
     nfgen_family: u8,
     inner: NonNull<nflog_data>,
@@ -147,10 +268,12 @@ impl<'a> Message<'a> {
     /// Depending on set_mode, we may not have a payload
     /// The actual amount and type of data retrieved by this function will
     /// depend on the mode set with the [CopyMode](CopyMode).
+    /// If the packet is larger than the copy range, only its prefix is
+    /// returned (see [is_truncated](Message::is_truncated)).
     pub fn payload(&self) -> Option<&'a [u8]> {
         let mut c_ptr = std::ptr::null_mut();
         let payload_len = unsafe { nflog_get_payload(self.inner.as_ptr(), &mut c_ptr) };
-        if payload_len == 0 {
+        if payload_len <= 0 {
             return None;
         }
 
@@ -160,6 +283,59 @@ impl<'a> Message<'a> {
         Some(payload)
     }
 
+    /// Get the length of the packet before it was cut to the copy range.
+    ///
+    /// The length is taken from the IP header of the payload. Returns `None`
+    /// if there is no payload, it is not an IP packet or the length is not
+    /// known (IPv6 jumbograms).
+    pub fn original_length(&self) -> Option<usize> {
+        Payload(self.payload()).original_length()
+    }
+
+    /// Returns `true` if the payload is shorter than the original packet.
+    pub fn is_truncated(&self) -> bool {
+        Payload(self.payload()).is_truncated()
+    }
+
+    /// Walk the IPv6 extension headers of the payload.
+    ///
+    /// Returns `None` if there is no payload or it is not an IPv6 packet.
+    pub fn ipv6_ext_headers(&self) -> Option<Ipv6HeaderChain> {
+        Payload(self.payload()).ipv6_ext_headers()
+    }
+
+    /// Decode the IP header of the payload.
+    ///
+    /// Returns `None` if there is no payload.
+    pub fn ip_header(&self) -> Option<Result<IpHeader, DecodeError>> {
+        Payload(self.payload()).ip_header()
+    }
+
+    /// Get the normalized 5-tuple of the payload.
+    ///
+    /// Use [FlowKey::community_id](FlowKey::community_id) to get the
+    /// Community ID of the flow.
+    /// Returns `None` if there is no payload.
+    pub fn flow_key(&self) -> Option<Result<FlowKey, DecodeError>> {
+        Payload(self.payload()).flow_key()
+    }
+
+    /// Unwrap GRE, IP-in-IP, VXLAN and GENEVE tunnels of the payload to get
+    /// to the inner packet.
+    ///
+    /// Returns `None` if there is no payload.
+    pub fn decapsulate(&self) -> Option<Result<Decapsulated<'a>, DecodeError>> {
+        Payload(self.payload()).decapsulate()
+    }
+
+    /// Decode the ICMP or ICMPv6 error message carried by the payload,
+    /// including the original packet that triggered it.
+    ///
+    /// Returns `None` if there is no payload or it is not an ICMP error.
+    pub fn icmp_error(&self) -> Option<Result<IcmpError, DecodeError>> {
+        Payload(self.payload()).icmp_error()
+    }
+
     /// Get the logging string prefix (configured using `--nflog-prefix "..."`
     /// in iptables rules).
     pub fn prefix(&self) -> Cow<'a, str> {
@@ -212,4 +388,294 @@ impl<'a> Message<'a> {
             _ => None,
         }
     }
+
+    /// Copy the message so that it can be kept after the handler returns.
+    pub fn to_owned_message(&self) -> OwnedMessage {
+        OwnedMessage::from(*self)
+    }
+}
+
+/// Owned copy of a [Message](Message).
+///
+/// The accessors mirror the ones of [Message](Message).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedMessage {
+    pub(crate) address_family: Option<AddressFamily>,
+    pub(crate) hwtype: u16,
+    pub(crate) packet_hwhdr: Option<Bytes>,
+    pub(crate) packet_hwaddr: Option<MacAddr>,
+    pub(crate) l3_proto: L3Protocol,
+    pub(crate) nfmark: u32,
+    pub(crate) timestamp: Option<SystemTime>,
+    pub(crate) indev: u32,
+    pub(crate) physindev: u32,
+    pub(crate) outdev: u32,
+    pub(crate) physoutdev: u32,
+    pub(crate) payload: Option<Bytes>,
+    pub(crate) prefix: String,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) local_seqnum: Option<u32>,
+    pub(crate) global_seqnum: Option<u32>,
+}
+
+impl OwnedMessage {
+    pub fn address_family(&self) -> Option<AddressFamily> {
+        self.address_family
+    }
+
+    /// Get the hardware link layer type.
+    pub fn hwtype(&self) -> u16 {
+        self.hwtype
+    }
+
+    /// Get the hardware link layer header.
+    pub fn packet_hwhdr(&self) -> Option<&[u8]> {
+        self.packet_hwhdr.as_deref()
+    }
+
+    /// Get the hardware address associated with the given packet.
+    pub fn packet_hwaddr(&self) -> Option<MacAddr> {
+        self.packet_hwaddr
+    }
+
+    /// Returns the layer 3 protocol/EtherType of the packet (i.e. 0x0800 is IPv4).
+    pub fn l3_proto(&self) -> L3Protocol {
+        self.l3_proto
+    }
+
+    /// Get the packet mark.
+    pub fn nfmark(&self) -> u32 {
+        self.nfmark
+    }
+
+    /// Get the packet timestamp.
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
+
+    /// Get the interface that the packet was received through.
+    pub fn indev(&self) -> u32 {
+        self.indev
+    }
+
+    /// Get the physical interface that the packet was received through.
+    pub fn physindev(&self) -> u32 {
+        self.physindev
+    }
+
+    /// Get the interface that the packet will be routed out.
+    pub fn outdev(&self) -> u32 {
+        self.outdev
+    }
+
+    /// Get the physical interface that the packet will be routed out.
+    pub fn physoutdev(&self) -> u32 {
+        self.physoutdev
+    }
+
+    /// Get the packet payload.
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.payload.as_deref()
+    }
+
+    /// Get the length of the packet before it was cut to the copy range.
+    pub fn original_length(&self) -> Option<usize> {
+        Payload(self.payload()).original_length()
+    }
+
+    /// Returns `true` if the payload is shorter than the original packet.
+    pub fn is_truncated(&self) -> bool {
+        Payload(self.payload()).is_truncated()
+    }
+
+    /// Walk the IPv6 extension headers of the payload.
+    pub fn ipv6_ext_headers(&self) -> Option<Ipv6HeaderChain> {
+        Payload(self.payload()).ipv6_ext_headers()
+    }
+
+    /// Decode the IP header of the payload.
+    pub fn ip_header(&self) -> Option<Result<IpHeader, DecodeError>> {
+        Payload(self.payload()).ip_header()
+    }
+
+    /// Get the normalized 5-tuple of the payload.
+    pub fn flow_key(&self) -> Option<Result<FlowKey, DecodeError>> {
+        Payload(self.payload()).flow_key()
+    }
+
+    /// Unwrap GRE, IP-in-IP, VXLAN and GENEVE tunnels of the payload.
+    pub fn decapsulate(&self) -> Option<Result<Decapsulated<'_>, DecodeError>> {
+        Payload(self.payload()).decapsulate()
+    }
+
+    /// Decode the ICMP or ICMPv6 error message carried by the payload.
+    pub fn icmp_error(&self) -> Option<Result<IcmpError, DecodeError>> {
+        Payload(self.payload()).icmp_error()
+    }
+
+    /// Get the logging string prefix.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Get the UID of the user that has generated the packet.
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// Get the GID of the user that has generated the packet.
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    /// Get the local nflog sequence number.
+    pub fn local_seqnum(&self) -> Option<u32> {
+        self.local_seqnum
+    }
+
+    /// Get the global nflog sequence number.
+    pub fn global_seqnum(&self) -> Option<u32> {
+        self.global_seqnum
+    }
+}
+
+impl From<Message<'_>> for OwnedMessage {
+    fn from(msg: Message<'_>) -> Self {
+        Self {
+            address_family: msg.address_family(),
+            hwtype: msg.hwtype(),
+            packet_hwhdr: msg.packet_hwhdr().map(Bytes::copy_from_slice),
+            packet_hwaddr: msg.packet_hwaddr(),
+            l3_proto: msg.l3_proto(),
+            nfmark: msg.nfmark(),
+            timestamp: msg.timestamp(),
+            indev: msg.indev(),
+            physindev: msg.physindev(),
+            outdev: msg.outdev(),
+            physoutdev: msg.physoutdev(),
+            payload: msg.payload().map(Bytes::copy_from_slice),
+            prefix: msg.prefix().into_owned(),
+            uid: msg.uid(),
+            gid: msg.gid(),
+            local_seqnum: msg.local_seqnum(),
+            global_seqnum: msg.global_seqnum(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl OwnedMessage {
+    /// A message without payload, told apart by its mark.
+    pub(crate) fn with_nfmark(nfmark: u32) -> Self {
+        Self {
+            address_family: None,
+            hwtype: 0,
+            packet_hwhdr: None,
+            packet_hwaddr: None,
+            l3_proto: 0,
+            nfmark,
+            timestamp: None,
+            indev: 0,
+            physindev: 0,
+            outdev: 0,
+            physoutdev: 0,
+            payload: None,
+            prefix: String::new(),
+            uid: None,
+            gid: None,
+            local_seqnum: None,
+            global_seqnum: None,
+        }
+    }
+}
+
+/// Decoders of the payload shared by [Message](Message) and
+/// [OwnedMessage](OwnedMessage).
+#[derive(Clone, Copy)]
+struct Payload<'a>(Option<&'a [u8]>);
+
+impl<'a> Payload<'a> {
+    fn original_length(self) -> Option<usize> {
+        self.0.and_then(packet::total_len)
+    }
+
+    fn is_truncated(self) -> bool {
+        match (self.0, self.original_length()) {
+            (Some(payload), Some(len)) => payload.len() < len,
+            _ => false,
+        }
+    }
+
+    fn ipv6_ext_headers(self) -> Option<Ipv6HeaderChain> {
+        let payload = self.0?;
+        if packet::ip_version(payload) != Some(6) {
+            return None;
+        }
+
+        Some(Ipv6HeaderChain::parse(payload))
+    }
+
+    fn ip_header(self) -> Option<Result<IpHeader, DecodeError>> {
+        self.0.map(IpHeader::parse)
+    }
+
+    fn flow_key(self) -> Option<Result<FlowKey, DecodeError>> {
+        self.0.map(FlowKey::parse)
+    }
+
+    fn decapsulate(self) -> Option<Result<Decapsulated<'a>, DecodeError>> {
+        self.0.map(Decapsulated::parse)
+    }
+
+    fn icmp_error(self) -> Option<Result<IcmpError, DecodeError>> {
+        self.0
+            .and_then(|payload| IcmpError::parse(payload).transpose())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{build, ipproto};
+
+    fn with_payload(payload: Vec<u8>) -> OwnedMessage {
+        OwnedMessage {
+            payload: Some(Bytes::from(payload)),
+            ..OwnedMessage::with_nfmark(0)
+        }
+    }
+
+    #[test]
+    fn decoders_return_none_without_payload() {
+        let msg = OwnedMessage::with_nfmark(0);
+        assert!(msg.ip_header().is_none());
+        assert!(msg.flow_key().is_none());
+        assert!(msg.decapsulate().is_none());
+        assert!(msg.icmp_error().is_none());
+    }
+
+    #[test]
+    fn icmp_error_of_payload() {
+        let original = build::ipv4(ipproto::UDP, [10, 0, 0, 1], [10, 0, 0, 2], &[0; 8]);
+        let icmp = build::icmp(3, 1, &original);
+        let msg = with_payload(build::ipv4(
+            ipproto::ICMP,
+            [10, 0, 0, 254],
+            [10, 0, 0, 1],
+            &icmp,
+        ));
+        assert!(matches!(msg.icmp_error(), Some(Ok(_))));
+
+        let msg = with_payload(original);
+        assert!(msg.icmp_error().is_none());
+
+        let msg = with_payload(build::ipv4(
+            ipproto::ICMP,
+            [10, 0, 0, 254],
+            [10, 0, 0, 1],
+            &[],
+        ));
+        assert_eq!(msg.icmp_error(), Some(Err(DecodeError::Truncated)));
+    }
 }
@@ -0,0 +1,74 @@
+use libc::{c_uint, c_void, iovec, mmsghdr};
+use std::os::unix::prelude::RawFd;
+use std::{io, mem, ptr};
+
+/// Preallocated buffers to read many datagrams with one `recvmmsg` syscall.
+pub(crate) struct MmsgBuffers {
+    buffers: Vec<Box<[u8]>>,
+    iovecs: Vec<iovec>,
+    headers: Vec<mmsghdr>,
+}
+
+// The raw pointers in `iovecs` and `headers` only point into `buffers` and
+// `iovecs`, which are owned by the struct as well.
+unsafe impl Send for MmsgBuffers {}
+
+impl MmsgBuffers {
+    pub(crate) fn new(batch: usize, buffer_size: usize) -> Self {
+        let batch = batch.max(1);
+
+        Self {
+            buffers: (0..batch)
+                .map(|_| vec![0; buffer_size].into_boxed_slice())
+                .collect(),
+            iovecs: (0..batch)
+                .map(|_| iovec {
+                    iov_base: ptr::null_mut(),
+                    iov_len: 0,
+                })
+                .collect(),
+            headers: (0..batch).map(|_| unsafe { mem::zeroed() }).collect(),
+        }
+    }
+
+    /// Read available datagrams without blocking.
+    ///
+    /// Returns the number of datagrams read, each of them can be accessed
+    /// with [datagram](MmsgBuffers::datagram).
+    pub(crate) fn recv(&mut self, fd: RawFd) -> io::Result<usize> {
+        for ((buffer, iovec), header) in self
+            .buffers
+            .iter_mut()
+            .zip(self.iovecs.iter_mut())
+            .zip(self.headers.iter_mut())
+        {
+            iovec.iov_base = buffer.as_mut_ptr() as *mut c_void;
+            iovec.iov_len = buffer.len();
+
+            *header = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                self.headers.as_mut_ptr(),
+                self.headers.len() as c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n as usize)
+    }
+
+    /// Returns the `i`-th datagram read by the last [recv](MmsgBuffers::recv).
+    pub(crate) fn datagram(&mut self, i: usize) -> &mut [u8] {
+        let len = self.headers[i].msg_len as usize;
+        &mut self.buffers[i][..len]
+    }
+}
@@ -0,0 +1,324 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+use super::icmp::InnerPacket;
+use super::ip::{self, IpHeader};
+use super::{ipproto, DecodeError};
+
+/// Normalized 5-tuple identifying a flow.
+///
+/// Both directions of a flow produce the same key: the endpoints are ordered
+/// so that `(src, src_port)` is the smaller one. For ICMP and ICMPv6 the ports
+/// hold the message type and either the type of the counterpart message (i.e.
+/// echo reply for echo request) or, for messages without one, the code. Such
+/// one-way messages are never reordered.
+///
+/// This follows the tuple definition of [Community ID] v1.
+///
+/// [Community ID]: https://github.com/corelight/community-id-spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Upper-layer protocol (i.e. 6 is TCP).
+    pub protocol: u8,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+}
+
+impl FlowKey {
+    /// Build a normalized key from the endpoints of a packet.
+    ///
+    /// For ICMP and ICMPv6 pass the message type and code as the source and
+    /// destination ports.
+    pub fn new(
+        src: IpAddr,
+        dst: IpAddr,
+        protocol: u8,
+        src_port: Option<u16>,
+        dst_port: Option<u16>,
+    ) -> Self {
+        let mut key = Self {
+            src,
+            dst,
+            protocol,
+            src_port,
+            dst_port,
+        };
+
+        let counterpart = match (protocol, src_port) {
+            (ipproto::ICMP, Some(icmp_type)) => Some(icmp_counterpart(icmp_type)),
+            (ipproto::ICMPV6, Some(icmp_type)) => Some(icmpv6_counterpart(icmp_type)),
+            _ => None,
+        };
+        let one_way = match counterpart {
+            Some(Some(counterpart)) => {
+                key.dst_port = Some(counterpart);
+                false
+            }
+            Some(None) => true,
+            None => false,
+        };
+
+        if !one_way && (key.dst, key.dst_port) < (key.src, key.src_port) {
+            std::mem::swap(&mut key.src, &mut key.dst);
+            std::mem::swap(&mut key.src_port, &mut key.dst_port);
+        }
+
+        key
+    }
+
+    /// Build the key of an IP packet.
+    pub fn parse(packet: &[u8]) -> Result<Self, DecodeError> {
+        let ip = IpHeader::parse(packet)?;
+        let data = ip.payload(packet);
+
+        let ports = if !ip.has_upper_layer() {
+            None
+        } else if ip.protocol == ipproto::ICMP || ip.protocol == ipproto::ICMPV6 {
+            if data.len() < 2 {
+                return Err(DecodeError::Truncated);
+            }
+            Some((data[0] as u16, data[1] as u16))
+        } else if ip::has_ports(ip.protocol) {
+            Some(ip::ports(ip.protocol, data).ok_or(DecodeError::Truncated)?)
+        } else {
+            None
+        };
+        let (src_port, dst_port) = ports.unzip();
+
+        Ok(Self::new(ip.src, ip.dst, ip.protocol, src_port, dst_port))
+    }
+
+    /// Returns the Community ID v1 string of the flow with the default seed 0.
+    pub fn community_id(&self) -> String {
+        self.community_id_with_seed(0)
+    }
+
+    /// Returns the Community ID v1 string of the flow.
+    pub fn community_id_with_seed(&self, seed: u16) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(seed.to_be_bytes());
+        match (self.src, self.dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                hasher.update(src.octets());
+                hasher.update(dst.octets());
+            }
+            (src, dst) => {
+                hasher.update(to_ipv6_octets(src));
+                hasher.update(to_ipv6_octets(dst));
+            }
+        }
+        hasher.update([self.protocol, 0]);
+        if let (Some(src_port), Some(dst_port)) = (self.src_port, self.dst_port) {
+            hasher.update(src_port.to_be_bytes());
+            hasher.update(dst_port.to_be_bytes());
+        }
+
+        format!("1:{}", base64::encode(hasher.finalize()))
+    }
+}
+
+impl InnerPacket {
+    /// Returns the key of the original flow that triggered the error.
+    pub fn flow_key(&self) -> FlowKey {
+        FlowKey::new(
+            self.src,
+            self.dst,
+            self.protocol,
+            self.src_port,
+            self.dst_port,
+        )
+    }
+}
+
+fn to_ipv6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+fn icmp_counterpart(icmp_type: u16) -> Option<u16> {
+    match icmp_type {
+        // Echo
+        8 => Some(0),
+        0 => Some(8),
+        // Router Solicitation/Advertisement
+        10 => Some(9),
+        9 => Some(10),
+        // Timestamp
+        13 => Some(14),
+        14 => Some(13),
+        // Information
+        15 => Some(16),
+        16 => Some(15),
+        // Address Mask
+        17 => Some(18),
+        18 => Some(17),
+        _ => None,
+    }
+}
+
+fn icmpv6_counterpart(icmp_type: u16) -> Option<u16> {
+    match icmp_type {
+        // Echo
+        128 => Some(129),
+        129 => Some(128),
+        // Multicast Listener Query/Report
+        130 => Some(131),
+        131 => Some(130),
+        // Router Solicitation/Advertisement
+        133 => Some(134),
+        134 => Some(133),
+        // Neighbor Solicitation/Advertisement
+        135 => Some(136),
+        136 => Some(135),
+        // Node Information Query/Response
+        139 => Some(140),
+        140 => Some(139),
+        // Home Agent Address Discovery
+        144 => Some(145),
+        145 => Some(144),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::build;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn v4(addr: [u8; 4]) -> IpAddr {
+        Ipv4Addr::from(addr).into()
+    }
+
+    fn v6(addr: &str) -> IpAddr {
+        addr.parse::<Ipv6Addr>().unwrap().into()
+    }
+
+    // The baseline vectors of the Community ID specification.
+
+    #[test]
+    fn community_id_tcp() {
+        let key = FlowKey::new(
+            v4([128, 232, 110, 120]),
+            v4([66, 35, 250, 204]),
+            ipproto::TCP,
+            Some(34855),
+            Some(80),
+        );
+        assert_eq!(key.community_id(), "1:LQU9qZlK+B5F3KDmev6m5PMibrg=");
+        assert_eq!(
+            key.community_id_with_seed(1),
+            "1:3V71V58M3Ksw/yuFALMcW0LAHvc="
+        );
+    }
+
+    #[test]
+    fn community_id_udp() {
+        let key = FlowKey::new(
+            v4([192, 168, 1, 52]),
+            v4([8, 8, 8, 8]),
+            ipproto::UDP,
+            Some(54585),
+            Some(53),
+        );
+        assert_eq!(key.community_id(), "1:d/FP5EW3wiY1vCndhwleRRKHowQ=");
+    }
+
+    #[test]
+    fn community_id_icmp() {
+        let key = FlowKey::new(
+            v4([192, 168, 0, 89]),
+            v4([192, 168, 0, 1]),
+            ipproto::ICMP,
+            Some(8),
+            Some(0),
+        );
+        assert_eq!(key.community_id(), "1:X0snYXpgwiv9TZtqg64sgzUn6Dk=");
+    }
+
+    #[test]
+    fn community_id_icmp6() {
+        let key = FlowKey::new(
+            v6("fe80::200:86ff:fe05:80da"),
+            v6("fe80::260:97ff:fe07:69ea"),
+            ipproto::ICMPV6,
+            Some(135),
+            Some(0),
+        );
+        assert_eq!(key.community_id(), "1:dGHyGvjMfljg6Bppwm3bg0LO8TY=");
+    }
+
+    #[test]
+    fn both_directions_have_the_same_key() {
+        let request = build::ipv4(
+            ipproto::TCP,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+            &build::tcp(50000, 22),
+        );
+        let reply = build::ipv4(
+            ipproto::TCP,
+            [10, 0, 0, 1],
+            [10, 0, 0, 2],
+            &build::tcp(22, 50000),
+        );
+
+        let key = FlowKey::parse(&request).unwrap();
+        assert_eq!(key, FlowKey::parse(&reply).unwrap());
+        assert_eq!((key.src, key.src_port), (v4([10, 0, 0, 1]), Some(22)));
+    }
+
+    #[test]
+    fn icmp_echo_pairs_with_its_reply() {
+        let request = build::ipv4(
+            ipproto::ICMP,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+            &build::icmp(8, 0, &[]),
+        );
+        let reply = build::ipv4(
+            ipproto::ICMP,
+            [10, 0, 0, 1],
+            [10, 0, 0, 2],
+            &build::icmp(0, 0, &[]),
+        );
+
+        assert_eq!(
+            FlowKey::parse(&request).unwrap(),
+            FlowKey::parse(&reply).unwrap()
+        );
+    }
+
+    #[test]
+    fn one_way_icmp_is_not_reordered() {
+        let key = FlowKey::new(
+            v4([10, 0, 0, 2]),
+            v4([10, 0, 0, 1]),
+            ipproto::ICMP,
+            Some(3),
+            Some(1),
+        );
+
+        assert_eq!((key.src, key.dst), (v4([10, 0, 0, 2]), v4([10, 0, 0, 1])));
+        assert_eq!((key.src_port, key.dst_port), (Some(3), Some(1)));
+    }
+
+    #[test]
+    fn truncated_ports() {
+        let packet = build::ipv4(ipproto::UDP, [10, 0, 0, 1], [10, 0, 0, 2], &[0, 53]);
+        assert_eq!(FlowKey::parse(&packet), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn protocol_without_ports() {
+        let packet = build::ipv4(ipproto::GRE, [10, 0, 0, 2], [10, 0, 0, 1], &[0; 4]);
+
+        let key = FlowKey::parse(&packet).unwrap();
+        assert_eq!((key.src_port, key.dst_port), (None, None));
+        assert_eq!(key.src, v4([10, 0, 0, 1]));
+    }
+}
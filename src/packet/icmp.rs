@@ -0,0 +1,207 @@
+use std::net::IpAddr;
+
+use super::ip::{self, IpHeader};
+use super::{ipproto, DecodeError};
+
+const ICMP_HEADER_LEN: usize = 8;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_SOURCE_QUENCH: u8 = 4;
+const ICMP_REDIRECT: u8 = 5;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETER_PROB: u8 = 12;
+
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_PARAM_PROB: u8 = 4;
+
+/// An ICMP or ICMPv6 error message together with the packet that triggered it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpError {
+    /// Address of the host that generated the error.
+    pub reporter: IpAddr,
+    pub icmp_type: u8,
+    pub code: u8,
+    /// The original packet quoted by the error.
+    pub inner: InnerPacket,
+}
+
+/// The original packet quoted by an ICMP error.
+///
+/// Routers quote only the start of the packet, usually the IP header and the
+/// first 8 bytes of the transport header, so there is no payload here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InnerPacket {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Upper-layer protocol (i.e. 6 is TCP).
+    pub protocol: u8,
+    /// Source port for TCP, UDP, UDP-Lite, SCTP and DCCP.
+    pub src_port: Option<u16>,
+    /// Destination port for TCP, UDP, UDP-Lite, SCTP and DCCP.
+    pub dst_port: Option<u16>,
+}
+
+impl IcmpError {
+    /// Decode the ICMP error carried by an IP packet.
+    ///
+    /// Returns `Ok(None)` if the packet is not an ICMP or ICMPv6 error message.
+    pub fn parse(packet: &[u8]) -> Result<Option<Self>, DecodeError> {
+        let outer = IpHeader::parse(packet)?;
+        if !outer.has_upper_layer() {
+            return Ok(None);
+        }
+
+        let icmp = outer.payload(packet);
+        let is_error = match (outer.protocol, icmp.first()) {
+            (ipproto::ICMP, Some(&t)) => is_icmp_error(t),
+            (ipproto::ICMPV6, Some(&t)) => is_icmpv6_error(t),
+            (ipproto::ICMP, None) | (ipproto::ICMPV6, None) => return Err(DecodeError::Truncated),
+            _ => false,
+        };
+        if !is_error {
+            return Ok(None);
+        }
+        if icmp.len() < ICMP_HEADER_LEN {
+            return Err(DecodeError::Truncated);
+        }
+
+        let quoted = &icmp[ICMP_HEADER_LEN..];
+        let inner = IpHeader::parse(quoted)?;
+        let (src_port, dst_port) = if inner.has_upper_layer() {
+            ip::ports(inner.protocol, inner.payload(quoted)).unzip()
+        } else {
+            (None, None)
+        };
+
+        Ok(Some(Self {
+            reporter: outer.src,
+            icmp_type: icmp[0],
+            code: icmp[1],
+            inner: InnerPacket {
+                src: inner.src,
+                dst: inner.dst,
+                protocol: inner.protocol,
+                src_port,
+                dst_port,
+            },
+        }))
+    }
+}
+
+fn is_icmp_error(icmp_type: u8) -> bool {
+    matches!(
+        icmp_type,
+        ICMP_DEST_UNREACH
+            | ICMP_SOURCE_QUENCH
+            | ICMP_REDIRECT
+            | ICMP_TIME_EXCEEDED
+            | ICMP_PARAMETER_PROB
+    )
+}
+
+fn is_icmpv6_error(icmp_type: u8) -> bool {
+    matches!(
+        icmp_type,
+        ICMPV6_DEST_UNREACH | ICMPV6_PACKET_TOO_BIG | ICMPV6_TIME_EXCEEDED | ICMPV6_PARAM_PROB
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::build;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const HOST: [u8; 4] = [192, 0, 2, 1];
+    const SERVER: [u8; 4] = [198, 51, 100, 7];
+    const ROUTER: [u8; 4] = [203, 0, 113, 254];
+
+    const HOST6: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const SERVER6: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7];
+    const ROUTER6: [u8; 16] = [
+        0x20, 0x01, 0x0d, 0xb8, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+    ];
+
+    /// Quote the IP header and the first 8 bytes of its payload, as routers do.
+    fn quote(packet: &[u8], header_len: usize) -> &[u8] {
+        &packet[..header_len + 8]
+    }
+
+    #[test]
+    fn quotes_ipv4_packet() {
+        let original = build::ipv4(ipproto::UDP, HOST, SERVER, &build::udp(5353, 53, &[0; 32]));
+        let icmp = build::icmp(ICMP_DEST_UNREACH, 3, quote(&original, 20));
+        let packet = build::ipv4(ipproto::ICMP, ROUTER, HOST, &icmp);
+
+        let error = IcmpError::parse(&packet).unwrap().unwrap();
+        assert_eq!(error.reporter, Ipv4Addr::from(ROUTER));
+        assert_eq!((error.icmp_type, error.code), (ICMP_DEST_UNREACH, 3));
+        assert_eq!(
+            error.inner,
+            InnerPacket {
+                src: Ipv4Addr::from(HOST).into(),
+                dst: Ipv4Addr::from(SERVER).into(),
+                protocol: ipproto::UDP,
+                src_port: Some(5353),
+                dst_port: Some(53),
+            }
+        );
+    }
+
+    #[test]
+    fn quotes_ipv6_packet() {
+        let mut payload = build::fragment(ipproto::TCP, 0, true, 1);
+        payload.extend(build::tcp(40000, 443));
+        let original = build::ipv6(ipproto::FRAGMENT, HOST6, SERVER6, &payload);
+        let icmp = build::icmp(ICMPV6_TIME_EXCEEDED, 0, quote(&original, 48));
+        let packet = build::ipv6(ipproto::ICMPV6, ROUTER6, HOST6, &icmp);
+
+        let error = IcmpError::parse(&packet).unwrap().unwrap();
+        assert_eq!(error.reporter, Ipv6Addr::from(ROUTER6));
+        assert_eq!((error.icmp_type, error.code), (ICMPV6_TIME_EXCEEDED, 0));
+        assert_eq!(error.inner.src, Ipv6Addr::from(HOST6));
+        assert_eq!(error.inner.dst, Ipv6Addr::from(SERVER6));
+        assert_eq!(error.inner.protocol, ipproto::TCP);
+        assert_eq!(error.inner.src_port, Some(40000));
+        assert_eq!(error.inner.dst_port, Some(443));
+    }
+
+    #[test]
+    fn quote_without_ports() {
+        let original = build::ipv4(ipproto::TCP, HOST, SERVER, &build::tcp(1, 2));
+        let icmp = build::icmp(ICMP_TIME_EXCEEDED, 0, &original[..22]);
+        let packet = build::ipv4(ipproto::ICMP, ROUTER, HOST, &icmp);
+
+        let error = IcmpError::parse(&packet).unwrap().unwrap();
+        assert_eq!(error.inner.protocol, ipproto::TCP);
+        assert_eq!((error.inner.src_port, error.inner.dst_port), (None, None));
+    }
+
+    #[test]
+    fn truncated_quote() {
+        let original = build::ipv4(ipproto::UDP, HOST, SERVER, &build::udp(1, 2, &[]));
+        let icmp = build::icmp(ICMP_DEST_UNREACH, 1, &original[..12]);
+        let packet = build::ipv4(ipproto::ICMP, ROUTER, HOST, &icmp);
+        assert_eq!(IcmpError::parse(&packet), Err(DecodeError::Truncated));
+
+        let icmp = build::icmp(ICMP_DEST_UNREACH, 1, &[]);
+        let packet = build::ipv4(ipproto::ICMP, ROUTER, HOST, &icmp[..4]);
+        assert_eq!(IcmpError::parse(&packet), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let echo = build::icmp(8, 0, &[0; 16]);
+        let packet = build::ipv4(ipproto::ICMP, HOST, SERVER, &echo);
+        assert_eq!(IcmpError::parse(&packet), Ok(None));
+
+        let echo = build::icmp(128, 0, &[0; 16]);
+        let packet = build::ipv6(ipproto::ICMPV6, HOST6, SERVER6, &echo);
+        assert_eq!(IcmpError::parse(&packet), Ok(None));
+
+        let packet = build::ipv4(ipproto::UDP, HOST, SERVER, &build::udp(1, 2, &[3; 8]));
+        assert_eq!(IcmpError::parse(&packet), Ok(None));
+    }
+}
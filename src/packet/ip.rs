@@ -0,0 +1,242 @@
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::ipv6::{Ipv6HeaderChain, IPV6_HEADER_LEN};
+use super::{ipproto, DecodeError};
+
+const IPV4_HEADER_LEN: usize = 20;
+
+/// Addresses and upper-layer protocol of an IPv4 or IPv6 packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpHeader {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Upper-layer protocol (i.e. 6 is TCP).
+    ///
+    /// For IPv6 the extension headers are skipped.
+    pub protocol: u8,
+    /// Offset of the upper-layer header from the start of the packet.
+    pub payload_offset: usize,
+    /// Offset of the fragment data in 8-octet units, 0 if the packet is not
+    /// fragmented or is the first fragment.
+    pub fragment_offset: u16,
+    /// Length of the packet declared by the header, `None` for IPv6 jumbograms.
+    pub total_len: Option<usize>,
+}
+
+impl IpHeader {
+    /// Parse the IP header at the start of the packet.
+    pub fn parse(packet: &[u8]) -> Result<Self, DecodeError> {
+        match super::ip_version(packet) {
+            Some(4) => Self::parse_v4(packet),
+            Some(6) => Self::parse_v6(packet),
+            Some(_) => Err(DecodeError::Malformed),
+            None => Err(DecodeError::Truncated),
+        }
+    }
+
+    fn parse_v4(packet: &[u8]) -> Result<Self, DecodeError> {
+        if packet.len() < IPV4_HEADER_LEN {
+            return Err(DecodeError::Truncated);
+        }
+
+        let header_len = (packet[0] & 0x0f) as usize * 4;
+        if header_len < IPV4_HEADER_LEN {
+            return Err(DecodeError::Malformed);
+        }
+        let total_len = total_len(packet);
+        if matches!(total_len, Some(len) if len < header_len) {
+            return Err(DecodeError::Malformed);
+        }
+        if packet.len() < header_len {
+            return Err(DecodeError::Truncated);
+        }
+
+        let src: [u8; 4] = packet[12..16].try_into().unwrap();
+        let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+        let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+
+        Ok(Self {
+            src: Ipv4Addr::from(src).into(),
+            dst: Ipv4Addr::from(dst).into(),
+            protocol: packet[9],
+            payload_offset: header_len,
+            fragment_offset,
+            total_len,
+        })
+    }
+
+    fn parse_v6(packet: &[u8]) -> Result<Self, DecodeError> {
+        if packet.len() < IPV6_HEADER_LEN {
+            return Err(DecodeError::Truncated);
+        }
+
+        let total_len = total_len(packet);
+        let chain = Ipv6HeaderChain::parse(captured(packet, total_len));
+        if chain.malformed {
+            return Err(DecodeError::Malformed);
+        }
+        if chain.truncated {
+            return Err(DecodeError::Truncated);
+        }
+
+        let src: [u8; 16] = packet[8..24].try_into().unwrap();
+        let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+
+        Ok(Self {
+            src: Ipv6Addr::from(src).into(),
+            dst: Ipv6Addr::from(dst).into(),
+            protocol: chain.protocol,
+            payload_offset: chain.offset,
+            fragment_offset: chain.fragment.map_or(0, |f| f.offset),
+            total_len,
+        })
+    }
+
+    /// Returns the upper-layer part of the packet.
+    ///
+    /// Bytes past the length declared by the header are not included.
+    pub fn payload<'a>(&self, packet: &'a [u8]) -> &'a [u8] {
+        let packet = captured(packet, self.total_len);
+        packet.get(self.payload_offset..).unwrap_or_default()
+    }
+
+    /// Returns `true` if the packet is shorter than the length declared by
+    /// the header.
+    pub fn is_truncated(&self, packet: &[u8]) -> bool {
+        matches!(self.total_len, Some(len) if packet.len() < len)
+    }
+
+    /// Returns `true` if the upper-layer header is carried by this packet.
+    pub fn has_upper_layer(&self) -> bool {
+        self.fragment_offset == 0 && self.protocol != ipproto::NONE
+    }
+}
+
+/// Returns the length of the packet declared by the IP header.
+///
+/// Only the fixed part of the header has to be captured. Returns `None` if it
+/// is not, or for IPv6 jumbograms whose length is carried by an option.
+pub(crate) fn total_len(packet: &[u8]) -> Option<usize> {
+    match super::ip_version(packet)? {
+        4 if packet.len() >= 4 => Some(u16::from_be_bytes([packet[2], packet[3]]) as usize),
+        6 if packet.len() >= 6 => match u16::from_be_bytes([packet[4], packet[5]]) {
+            0 => None,
+            len => Some(IPV6_HEADER_LEN + len as usize),
+        },
+        _ => None,
+    }
+}
+
+fn captured(packet: &[u8], total_len: Option<usize>) -> &[u8] {
+    match total_len {
+        Some(len) if len < packet.len() => &packet[..len],
+        _ => packet,
+    }
+}
+
+/// Returns `true` if the protocol header starts with source and destination ports.
+pub(crate) fn has_ports(protocol: u8) -> bool {
+    matches!(
+        protocol,
+        ipproto::TCP | ipproto::UDP | ipproto::DCCP | ipproto::SCTP | ipproto::UDPLITE
+    )
+}
+
+/// Returns the source and destination ports if the protocol has them.
+pub(crate) fn ports(protocol: u8, data: &[u8]) -> Option<(u16, u16)> {
+    if !has_ports(protocol) || data.len() < 4 {
+        return None;
+    }
+
+    Some((
+        u16::from_be_bytes([data[0], data[1]]),
+        u16::from_be_bytes([data[2], data[3]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::build;
+
+    const SRC: [u8; 4] = [10, 0, 0, 1];
+    const DST: [u8; 4] = [10, 0, 0, 2];
+
+    #[test]
+    fn truncated_by_copy_range() {
+        let packet = build::ipv4(ipproto::UDP, SRC, DST, &build::udp(1, 2, &[0xaa; 1000]));
+        let ip = IpHeader::parse(&packet).unwrap();
+        assert_eq!(ip.total_len, Some(1028));
+        assert!(!ip.is_truncated(&packet));
+
+        // The kernel copied only the first 64 bytes of the packet.
+        let copied = &packet[..64];
+        let ip = IpHeader::parse(copied).unwrap();
+        assert!(ip.is_truncated(copied));
+        assert_eq!(ip.payload(copied).len(), 44);
+    }
+
+    #[test]
+    fn padding_is_not_payload() {
+        let mut packet = build::ipv4(ipproto::UDP, SRC, DST, &build::udp(1, 2, &[]));
+        // Ethernet pads short frames to 60 bytes.
+        packet.resize(46, 0);
+
+        let ip = IpHeader::parse(&packet).unwrap();
+        assert!(!ip.is_truncated(&packet));
+        assert_eq!(ip.payload(&packet), &build::udp(1, 2, &[])[..]);
+    }
+
+    #[test]
+    fn ipv6_payload_length() {
+        let src = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let dst = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        let packet = build::ipv6(ipproto::UDP, src, dst, &build::udp(1, 2, &[0; 100]));
+
+        let ip = IpHeader::parse(&packet).unwrap();
+        assert_eq!(ip.total_len, Some(148));
+        assert!(!ip.is_truncated(&packet));
+        assert!(ip.is_truncated(&packet[..100]));
+
+        // Jumbograms carry their length in an option, it is never known
+        // to be truncated.
+        let mut jumbo = packet.clone();
+        jumbo[4..6].copy_from_slice(&[0, 0]);
+        let ip = IpHeader::parse(&jumbo[..100]).unwrap();
+        assert_eq!(ip.total_len, None);
+        assert!(!ip.is_truncated(&jumbo[..100]));
+    }
+
+    #[test]
+    fn total_len_needs_only_the_fixed_header() {
+        let packet = build::ipv4(ipproto::TCP, SRC, DST, &build::tcp(1, 2));
+
+        assert_eq!(total_len(&packet[..4]), Some(40));
+        assert_eq!(total_len(&packet[..3]), None);
+        assert_eq!(total_len(&[]), None);
+    }
+
+    #[test]
+    fn invalid_ipv4_lengths() {
+        let mut packet = build::ipv4(ipproto::TCP, SRC, DST, &build::tcp(1, 2));
+        packet[0] = 0x44;
+        assert_eq!(IpHeader::parse(&packet), Err(DecodeError::Malformed));
+
+        let mut packet = build::ipv4(ipproto::TCP, SRC, DST, &build::tcp(1, 2));
+        packet[2..4].copy_from_slice(&16u16.to_be_bytes());
+        assert_eq!(IpHeader::parse(&packet), Err(DecodeError::Malformed));
+
+        assert_eq!(IpHeader::parse(&packet[..19]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn fragments_have_no_upper_layer() {
+        let mut packet = build::ipv4(ipproto::UDP, SRC, DST, &[0; 16]);
+        packet[6..8].copy_from_slice(&(0x2000u16 | 3).to_be_bytes());
+
+        let ip = IpHeader::parse(&packet).unwrap();
+        assert_eq!(ip.fragment_offset, 3);
+        assert!(!ip.has_upper_layer());
+    }
+}
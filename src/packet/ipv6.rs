@@ -0,0 +1,314 @@
+use super::ipproto;
+
+pub(crate) const IPV6_HEADER_LEN: usize = 40;
+
+// Guards against crafted packets with endless chains of extension headers.
+const MAX_EXT_HEADERS: usize = 16;
+
+/// An IPv6 extension header found in the packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6ExtHeader {
+    /// Protocol number of the header (i.e. 44 is Fragment).
+    pub protocol: u8,
+    /// Offset of the header from the start of the packet.
+    pub offset: usize,
+    /// Length of the header in bytes.
+    pub len: usize,
+}
+
+/// Fields of an IPv6 Fragment header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Fragment {
+    /// Offset of the fragment data in 8-octet units.
+    pub offset: u16,
+    /// Whether more fragments follow.
+    pub more: bool,
+    pub identification: u32,
+}
+
+impl Ipv6Fragment {
+    /// Returns `true` if the fragment carries the start of the upper-layer header.
+    pub fn is_first(&self) -> bool {
+        self.offset == 0
+    }
+}
+
+/// Chain of IPv6 extension headers that precede the upper-layer protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6HeaderChain {
+    /// Extension headers in the order they appear in the packet.
+    pub headers: Vec<Ipv6ExtHeader>,
+    /// Upper-layer protocol (i.e. 6 is TCP).
+    ///
+    /// If the chain is truncated or malformed, this is the protocol of the
+    /// header at which the walk stopped.
+    pub protocol: u8,
+    /// Offset of the upper-layer header from the start of the packet.
+    pub offset: usize,
+    /// Fragment header, if the packet is a fragment.
+    pub fragment: Option<Ipv6Fragment>,
+    /// The packet ends before the chain does.
+    pub truncated: bool,
+    /// The chain violates RFC 8200 (i.e. Hop-by-Hop Options not first).
+    pub malformed: bool,
+}
+
+impl Ipv6HeaderChain {
+    /// Walk the extension headers of an IPv6 packet starting at the fixed header.
+    pub fn parse(packet: &[u8]) -> Self {
+        let mut chain = Self {
+            headers: Vec::new(),
+            protocol: ipproto::NONE,
+            offset: IPV6_HEADER_LEN,
+            fragment: None,
+            truncated: false,
+            malformed: false,
+        };
+
+        if packet.len() < IPV6_HEADER_LEN {
+            chain.offset = packet.len();
+            chain.truncated = true;
+            return chain;
+        }
+        if super::ip_version(packet) != Some(6) {
+            chain.malformed = true;
+        }
+
+        let mut next = packet[6];
+        let mut offset = IPV6_HEADER_LEN;
+
+        while is_ext_header(next) {
+            if next == ipproto::HOPOPTS && !chain.headers.is_empty() {
+                chain.malformed = true;
+                break;
+            }
+            if chain.headers.len() == MAX_EXT_HEADERS {
+                chain.malformed = true;
+                break;
+            }
+
+            let hdr = &packet[offset..];
+            let len = match (next, hdr.get(1)) {
+                (ipproto::FRAGMENT, _) => 8,
+                (ipproto::AH, Some(&len)) => (len as usize + 2) * 4,
+                (_, Some(&len)) => (len as usize + 1) * 8,
+                (_, None) => {
+                    chain.truncated = true;
+                    break;
+                }
+            };
+            if hdr.len() < len {
+                chain.truncated = true;
+                break;
+            }
+
+            chain.headers.push(Ipv6ExtHeader {
+                protocol: next,
+                offset,
+                len,
+            });
+            next = hdr[0];
+            offset += len;
+
+            if chain.headers.last().map(|h| h.protocol) == Some(ipproto::FRAGMENT) {
+                let off_flags = u16::from_be_bytes([hdr[2], hdr[3]]);
+                let fragment = Ipv6Fragment {
+                    offset: off_flags >> 3,
+                    more: off_flags & 1 != 0,
+                    identification: u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]),
+                };
+                chain.fragment = Some(fragment);
+
+                // The rest of a non-first fragment is upper-layer data, not headers.
+                if !fragment.is_first() {
+                    break;
+                }
+            }
+        }
+
+        chain.protocol = next;
+        chain.offset = offset;
+        chain
+    }
+
+    /// Returns `true` if the upper-layer header can be read at
+    /// [offset](Ipv6HeaderChain::offset).
+    pub fn has_upper_layer(&self) -> bool {
+        !self.truncated
+            && !self.malformed
+            && self.protocol != ipproto::NONE
+            && !matches!(self.fragment, Some(f) if !f.is_first())
+    }
+}
+
+fn is_ext_header(protocol: u8) -> bool {
+    matches!(
+        protocol,
+        ipproto::HOPOPTS
+            | ipproto::ROUTING
+            | ipproto::FRAGMENT
+            | ipproto::AH
+            | ipproto::DSTOPTS
+            | ipproto::MH
+            | ipproto::HIP
+            | ipproto::SHIM6
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::build;
+
+    const SRC: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const DST: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+    fn packet(next: u8, headers: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = headers.concat();
+        payload.extend(build::tcp(1234, 80));
+        build::ipv6(next, SRC, DST, &payload)
+    }
+
+    #[test]
+    fn walks_the_chain() {
+        let packet = packet(
+            ipproto::HOPOPTS,
+            &[
+                build::ext_header(ipproto::ROUTING, 1),
+                build::ext_header(ipproto::DSTOPTS, 3),
+                build::ext_header(ipproto::TCP, 1),
+            ],
+        );
+
+        let chain = Ipv6HeaderChain::parse(&packet);
+        let headers: Vec<_> = chain
+            .headers
+            .iter()
+            .map(|h| (h.protocol, h.offset, h.len))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                (ipproto::HOPOPTS, 40, 8),
+                (ipproto::ROUTING, 48, 24),
+                (ipproto::DSTOPTS, 72, 8),
+            ]
+        );
+        assert_eq!(chain.protocol, ipproto::TCP);
+        assert_eq!(chain.offset, 80);
+        assert!(!chain.truncated && !chain.malformed);
+        assert!(chain.has_upper_layer());
+    }
+
+    #[test]
+    fn without_extension_headers() {
+        let chain = Ipv6HeaderChain::parse(&packet(ipproto::TCP, &[]));
+
+        assert!(chain.headers.is_empty());
+        assert_eq!(
+            (chain.protocol, chain.offset),
+            (ipproto::TCP, IPV6_HEADER_LEN)
+        );
+        assert!(chain.has_upper_layer());
+    }
+
+    #[test]
+    fn hop_by_hop_not_first_is_malformed() {
+        let packet = packet(
+            ipproto::DSTOPTS,
+            &[
+                build::ext_header(ipproto::HOPOPTS, 1),
+                build::ext_header(ipproto::TCP, 1),
+            ],
+        );
+
+        let chain = Ipv6HeaderChain::parse(&packet);
+        assert!(chain.malformed);
+        assert_eq!(chain.headers.len(), 1);
+        assert_eq!(chain.protocol, ipproto::HOPOPTS);
+        assert!(!chain.has_upper_layer());
+    }
+
+    #[test]
+    fn first_fragment_has_upper_layer() {
+        let packet = packet(
+            ipproto::FRAGMENT,
+            &[build::fragment(ipproto::TCP, 0, true, 0xdead_beef)],
+        );
+
+        let chain = Ipv6HeaderChain::parse(&packet);
+        let fragment = chain.fragment.unwrap();
+        assert!(fragment.is_first() && fragment.more);
+        assert_eq!(fragment.identification, 0xdead_beef);
+        assert_eq!((chain.protocol, chain.offset), (ipproto::TCP, 48));
+        assert!(chain.has_upper_layer());
+    }
+
+    #[test]
+    fn non_first_fragment_stops_the_walk() {
+        // The data of the fragment looks like another extension header.
+        let packet = packet(
+            ipproto::FRAGMENT,
+            &[
+                build::fragment(ipproto::DSTOPTS, 185, false, 7),
+                build::ext_header(ipproto::TCP, 1),
+            ],
+        );
+
+        let chain = Ipv6HeaderChain::parse(&packet);
+        let fragment = chain.fragment.unwrap();
+        assert_eq!((fragment.offset, fragment.more), (185, false));
+        assert_eq!(chain.headers.len(), 1);
+        assert_eq!((chain.protocol, chain.offset), (ipproto::DSTOPTS, 48));
+        assert!(!chain.truncated && !chain.malformed);
+        assert!(!chain.has_upper_layer());
+    }
+
+    #[test]
+    fn limits_the_number_of_headers() {
+        let chain_of = |n: usize| {
+            let mut headers = vec![build::ext_header(ipproto::DSTOPTS, 1); n];
+            headers[n - 1][0] = ipproto::TCP;
+            Ipv6HeaderChain::parse(&packet(ipproto::DSTOPTS, &headers))
+        };
+
+        let chain = chain_of(MAX_EXT_HEADERS);
+        assert!(!chain.malformed);
+        assert_eq!(chain.headers.len(), MAX_EXT_HEADERS);
+        assert_eq!(chain.protocol, ipproto::TCP);
+
+        let chain = chain_of(MAX_EXT_HEADERS + 1);
+        assert!(chain.malformed);
+        assert_eq!(chain.headers.len(), MAX_EXT_HEADERS);
+        assert!(!chain.has_upper_layer());
+    }
+
+    #[test]
+    fn truncated_chain() {
+        let packet = packet(ipproto::ROUTING, &[build::ext_header(ipproto::TCP, 4)]);
+
+        let chain = Ipv6HeaderChain::parse(&packet[..60]);
+        assert!(chain.truncated);
+        assert!(chain.headers.is_empty());
+        assert_eq!((chain.protocol, chain.offset), (ipproto::ROUTING, 40));
+
+        let chain = Ipv6HeaderChain::parse(&packet[..40]);
+        assert!(chain.truncated);
+
+        let chain = Ipv6HeaderChain::parse(&packet[..20]);
+        assert!(chain.truncated);
+        assert_eq!(chain.offset, 20);
+    }
+
+    #[test]
+    fn authentication_header_length() {
+        // The length of AH is in 4-octet units minus 2.
+        let mut ah = vec![0; 24];
+        ah[0] = ipproto::TCP;
+        ah[1] = 4;
+
+        let chain = Ipv6HeaderChain::parse(&packet(ipproto::AH, &[ah]));
+        assert_eq!(chain.headers[0].len, 24);
+        assert_eq!(chain.offset, 64);
+    }
+}
@@ -0,0 +1,146 @@
+mod flow;
+mod icmp;
+mod ip;
+mod ipv6;
+mod tunnel;
+
+use std::{error, fmt, io};
+
+pub use flow::FlowKey;
+pub use icmp::{IcmpError, InnerPacket};
+pub use ip::IpHeader;
+
+pub(crate) use ip::total_len;
+pub use ipv6::{Ipv6ExtHeader, Ipv6Fragment, Ipv6HeaderChain};
+pub use tunnel::{Decapsulated, TunnelKind, TunnelLayer};
+
+/// IP protocol numbers used by the decoders.
+pub(crate) mod ipproto {
+    pub const HOPOPTS: u8 = 0;
+    pub const ICMP: u8 = 1;
+    pub const IPIP: u8 = 4;
+    pub const TCP: u8 = 6;
+    pub const UDP: u8 = 17;
+    pub const DCCP: u8 = 33;
+    pub const IPV6: u8 = 41;
+    pub const ROUTING: u8 = 43;
+    pub const FRAGMENT: u8 = 44;
+    pub const GRE: u8 = 47;
+    pub const AH: u8 = 51;
+    pub const NONE: u8 = 59;
+    pub const ICMPV6: u8 = 58;
+    pub const DSTOPTS: u8 = 60;
+    pub const SCTP: u8 = 132;
+    pub const MH: u8 = 135;
+    pub const UDPLITE: u8 = 136;
+    pub const HIP: u8 = 139;
+    pub const SHIM6: u8 = 140;
+}
+
+/// Error returned when a packet cannot be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet ends before the header being decoded.
+    Truncated,
+    /// A header contains invalid values.
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => f.write_str("packet is truncated"),
+            DecodeError::Malformed => f.write_str("packet is malformed"),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Returns the IP version stored in the first nibble of the packet.
+pub(crate) fn ip_version(packet: &[u8]) -> Option<u8> {
+    packet.first().map(|b| b >> 4)
+}
+
+/// Builders of the packets used by the tests of the decoders.
+#[cfg(test)]
+pub(crate) mod build {
+    /// Build an IPv4 packet without options.
+    pub(crate) fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let total_len = (20 + payload.len()) as u16;
+
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Build an IPv6 packet, `payload` starts with the extension headers.
+    pub(crate) fn ipv6(next: u8, src: [u8; 16], dst: [u8; 16], payload: &[u8]) -> Vec<u8> {
+        let payload_len = payload.len() as u16;
+
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&payload_len.to_be_bytes());
+        packet.extend_from_slice(&[next, 64]);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Build an IPv6 extension header of `8 * units` bytes.
+    pub(crate) fn ext_header(next: u8, units: usize) -> Vec<u8> {
+        let mut header = vec![0; 8 * units];
+        header[0] = next;
+        header[1] = (units - 1) as u8;
+        header
+    }
+
+    /// Build an IPv6 Fragment header.
+    pub(crate) fn fragment(next: u8, offset: u16, more: bool, identification: u32) -> Vec<u8> {
+        let off_flags = offset << 3 | more as u16;
+
+        let mut header = vec![next, 0];
+        header.extend_from_slice(&off_flags.to_be_bytes());
+        header.extend_from_slice(&identification.to_be_bytes());
+        header
+    }
+
+    /// Build a UDP header followed by `payload`, without checksum.
+    pub(crate) fn udp(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let len = (8 + payload.len()) as u16;
+
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&src_port.to_be_bytes());
+        datagram.extend_from_slice(&dst_port.to_be_bytes());
+        datagram.extend_from_slice(&len.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    /// Build a minimal TCP header without options.
+    pub(crate) fn tcp(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut segment = vec![0; 20];
+        segment[..2].copy_from_slice(&src_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        segment[12] = 5 << 4;
+        segment
+    }
+
+    /// Build an ICMP or ICMPv6 message with an unused rest of header.
+    pub(crate) fn icmp(icmp_type: u8, code: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(data);
+        message
+    }
+}
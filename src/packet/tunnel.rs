@@ -0,0 +1,413 @@
+use super::flow::FlowKey;
+use super::ip::{self, IpHeader};
+use super::{ipproto, DecodeError};
+
+// Guards against crafted packets nesting tunnels without end.
+const MAX_DEPTH: usize = 8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_TEB: u16 = 0x6558;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+
+const GRE_HEADER_LEN: usize = 4;
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+const UDP_HEADER_LEN: usize = 8;
+const VXLAN_PORT: u16 = 4789;
+const VXLAN_HEADER_LEN: usize = 8;
+const GENEVE_PORT: u16 = 6081;
+const GENEVE_HEADER_LEN: usize = 8;
+
+/// Encapsulation protocol of a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelKind {
+    /// Generic Routing Encapsulation (RFC 2784).
+    Gre,
+    /// IPv4 or IPv6 directly encapsulated in IPv4 or IPv6 (IP-in-IP, 6in4).
+    IpInIp,
+    /// Virtual eXtensible LAN (RFC 7348).
+    Vxlan,
+    /// Generic Network Virtualization Encapsulation (RFC 8926).
+    Geneve,
+}
+
+/// A tunnel found in the packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelLayer {
+    pub kind: TunnelKind,
+    /// IP header that carries the tunnel.
+    pub outer: IpHeader,
+    /// Offset of the encapsulated IP packet from the start of the packet.
+    pub inner_offset: usize,
+    /// GRE key or VXLAN/GENEVE virtual network identifier.
+    pub id: Option<u32>,
+}
+
+/// A packet with its tunnel headers removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decapsulated<'a> {
+    /// Tunnels from the outermost to the innermost one.
+    pub layers: Vec<TunnelLayer>,
+    /// The outermost IP packet.
+    pub outer: &'a [u8],
+    /// The innermost IP packet, the same as `outer` if there are no tunnels.
+    pub inner: &'a [u8],
+}
+
+impl<'a> Decapsulated<'a> {
+    /// Recursively unwrap GRE, IP-in-IP, VXLAN and GENEVE tunnels.
+    ///
+    /// Unwrapping stops at the first packet that is not a supported tunnel.
+    /// Returns an error if the headers of a tunnel are truncated or malformed.
+    pub fn parse(packet: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut layers = Vec::new();
+        let mut inner = packet;
+        let mut offset = 0;
+
+        while layers.len() < MAX_DEPTH {
+            let outer = IpHeader::parse(inner)?;
+            let next = match decapsulate(&outer, inner)? {
+                Some(next) => next,
+                None => break,
+            };
+
+            let inner_offset = offset + next.offset;
+            layers.push(TunnelLayer {
+                kind: next.kind,
+                outer,
+                inner_offset,
+                id: next.id,
+            });
+            inner = &inner[next.offset..next.offset + next.len];
+            offset = inner_offset;
+        }
+
+        Ok(Self {
+            layers,
+            outer: packet,
+            inner,
+        })
+    }
+
+    /// Returns `true` if the packet was carried by at least one tunnel.
+    pub fn is_tunneled(&self) -> bool {
+        !self.layers.is_empty()
+    }
+
+    /// Get the normalized 5-tuple of the outermost packet.
+    pub fn outer_flow(&self) -> Result<FlowKey, DecodeError> {
+        FlowKey::parse(self.outer)
+    }
+
+    /// Get the normalized 5-tuple of the innermost packet.
+    pub fn inner_flow(&self) -> Result<FlowKey, DecodeError> {
+        FlowKey::parse(self.inner)
+    }
+}
+
+struct Encapsulated {
+    kind: TunnelKind,
+    offset: usize,
+    len: usize,
+    id: Option<u32>,
+}
+
+fn decapsulate(ip: &IpHeader, packet: &[u8]) -> Result<Option<Encapsulated>, DecodeError> {
+    if !ip.has_upper_layer() {
+        return Ok(None);
+    }
+
+    let data = ip.payload(packet);
+    let inner = match ip.protocol {
+        ipproto::IPIP | ipproto::IPV6 => Some((TunnelKind::IpInIp, 0, None)),
+        ipproto::GRE => gre(data)?.map(|(offset, id)| (TunnelKind::Gre, offset, id)),
+        ipproto::UDP => udp(data)?,
+        _ => None,
+    };
+
+    Ok(inner.map(|(kind, offset, id)| Encapsulated {
+        kind,
+        offset: ip.payload_offset + offset,
+        len: data.len() - offset,
+        id,
+    }))
+}
+
+/// Returns the offset of the encapsulated IP packet and the key.
+fn gre(data: &[u8]) -> Result<Option<(usize, Option<u32>)>, DecodeError> {
+    if data.len() < GRE_HEADER_LEN {
+        return Err(DecodeError::Truncated);
+    }
+
+    let flags = u16::from_be_bytes([data[0], data[1]]);
+    if flags & GRE_VERSION != 0 {
+        // PPTP uses version 1 to carry PPP, not IP.
+        return Ok(None);
+    }
+    let protocol = u16::from_be_bytes([data[2], data[3]]);
+
+    let mut offset = GRE_HEADER_LEN;
+    if flags & GRE_CHECKSUM != 0 {
+        offset += 4;
+    }
+    let mut key = None;
+    if flags & GRE_KEY != 0 {
+        let bytes = data.get(offset..offset + 4).ok_or(DecodeError::Truncated)?;
+        key = Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        offset += 4;
+    }
+    if flags & GRE_SEQUENCE != 0 {
+        offset += 4;
+    }
+    if data.len() < offset {
+        return Err(DecodeError::Truncated);
+    }
+
+    let inner = ethertype_payload(protocol, &data[offset..])?;
+    Ok(inner.map(|inner| (offset + inner, key)))
+}
+
+fn udp(data: &[u8]) -> Result<Option<(TunnelKind, usize, Option<u32>)>, DecodeError> {
+    let dst_port = match ip::ports(ipproto::UDP, data) {
+        Some((_, dst_port)) => dst_port,
+        None => return Err(DecodeError::Truncated),
+    };
+    let data = &data[UDP_HEADER_LEN.min(data.len())..];
+
+    let (kind, header_len, protocol) = match dst_port {
+        VXLAN_PORT => {
+            if data.len() < VXLAN_HEADER_LEN {
+                return Err(DecodeError::Truncated);
+            }
+            (TunnelKind::Vxlan, VXLAN_HEADER_LEN, ETHERTYPE_TEB)
+        }
+        GENEVE_PORT => {
+            if data.len() < GENEVE_HEADER_LEN {
+                return Err(DecodeError::Truncated);
+            }
+            let options_len = (data[0] & 0x3f) as usize * 4;
+            let protocol = u16::from_be_bytes([data[2], data[3]]);
+            (
+                TunnelKind::Geneve,
+                GENEVE_HEADER_LEN + options_len,
+                protocol,
+            )
+        }
+        _ => return Ok(None),
+    };
+    if data.len() < header_len {
+        return Err(DecodeError::Truncated);
+    }
+
+    // VXLAN and GENEVE share the position of the virtual network identifier.
+    let vni = u32::from_be_bytes([0, data[4], data[5], data[6]]);
+
+    let inner = ethertype_payload(protocol, &data[header_len..])?;
+    Ok(inner.map(|inner| (kind, UDP_HEADER_LEN + header_len + inner, Some(vni))))
+}
+
+/// Returns the offset of the IP packet carried as the given EtherType.
+fn ethertype_payload(ethertype: u16, data: &[u8]) -> Result<Option<usize>, DecodeError> {
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Ok(Some(0)),
+        ETHERTYPE_TEB => ethernet(data),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the offset of the IP packet carried by an Ethernet frame.
+fn ethernet(data: &[u8]) -> Result<Option<usize>, DecodeError> {
+    let mut offset = ETHERNET_HEADER_LEN - 2;
+    loop {
+        let ethertype = match data.get(offset..offset + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            None => return Err(DecodeError::Truncated),
+        };
+        offset += 2;
+
+        match ethertype {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ => offset += VLAN_TAG_LEN - 2,
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => return Ok(Some(offset)),
+            _ => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::build;
+    use std::net::Ipv4Addr;
+
+    const OUTER_SRC: [u8; 4] = [192, 0, 2, 1];
+    const OUTER_DST: [u8; 4] = [192, 0, 2, 2];
+    const INNER_SRC: [u8; 4] = [10, 0, 0, 1];
+    const INNER_DST: [u8; 4] = [10, 0, 0, 2];
+
+    fn inner_packet() -> Vec<u8> {
+        build::ipv4(ipproto::TCP, INNER_SRC, INNER_DST, &build::tcp(1234, 80))
+    }
+
+    /// Build an Ethernet frame with the given VLAN tags.
+    fn ethernet(tags: &[u16], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for &tpid in tags {
+            frame.extend_from_slice(&tpid.to_be_bytes());
+            frame.extend_from_slice(&[0, 42]);
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn gre_with_checksum_key_and_sequence() {
+        let flags = GRE_CHECKSUM | GRE_KEY | GRE_SEQUENCE;
+        let mut gre = Vec::new();
+        gre.extend_from_slice(&flags.to_be_bytes());
+        gre.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        gre.extend_from_slice(&[0xff; 4]);
+        gre.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        gre.extend_from_slice(&[0xee; 4]);
+        gre.extend(inner_packet());
+        let packet = build::ipv4(ipproto::GRE, OUTER_SRC, OUTER_DST, &gre);
+
+        let decap = Decapsulated::parse(&packet).unwrap();
+        assert_eq!(decap.layers.len(), 1);
+        let layer = decap.layers[0];
+        assert_eq!(layer.kind, TunnelKind::Gre);
+        assert_eq!(layer.id, Some(0x1234_5678));
+        assert_eq!(layer.inner_offset, 20 + 16);
+        assert_eq!(layer.outer.src, Ipv4Addr::from(OUTER_SRC));
+        assert_eq!(decap.inner, &inner_packet()[..]);
+
+        let flow = decap.inner_flow().unwrap();
+        assert_eq!(flow.protocol, ipproto::TCP);
+        assert_eq!(flow.src, Ipv4Addr::from(INNER_SRC));
+        assert_eq!(decap.outer_flow().unwrap().protocol, ipproto::GRE);
+    }
+
+    #[test]
+    fn gre_with_truncated_key() {
+        let mut gre = Vec::new();
+        gre.extend_from_slice(&GRE_KEY.to_be_bytes());
+        gre.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        gre.extend_from_slice(&[0; 2]);
+        let packet = build::ipv4(ipproto::GRE, OUTER_SRC, OUTER_DST, &gre);
+
+        assert_eq!(Decapsulated::parse(&packet), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn pptp_is_not_decapsulated() {
+        let mut gre = vec![0x30, 0x01];
+        gre.extend_from_slice(&0x880bu16.to_be_bytes());
+        gre.extend_from_slice(&[0; 8]);
+        let packet = build::ipv4(ipproto::GRE, OUTER_SRC, OUTER_DST, &gre);
+
+        let decap = Decapsulated::parse(&packet).unwrap();
+        assert!(!decap.is_tunneled());
+        assert_eq!(decap.inner, &packet[..]);
+    }
+
+    #[test]
+    fn vxlan_with_vlan_tags() {
+        let frame = ethernet(
+            &[ETHERTYPE_QINQ, ETHERTYPE_VLAN],
+            ETHERTYPE_IPV4,
+            &inner_packet(),
+        );
+        let mut vxlan = vec![0x08, 0, 0, 0, 0x12, 0x34, 0x56, 0];
+        vxlan.extend(frame);
+        let udp = build::udp(49152, VXLAN_PORT, &vxlan);
+        let packet = build::ipv4(ipproto::UDP, OUTER_SRC, OUTER_DST, &udp);
+
+        let decap = Decapsulated::parse(&packet).unwrap();
+        assert_eq!(decap.layers.len(), 1);
+        assert_eq!(decap.layers[0].kind, TunnelKind::Vxlan);
+        assert_eq!(decap.layers[0].id, Some(0x12_3456));
+        assert_eq!(decap.layers[0].inner_offset, 20 + 8 + 8 + 14 + 8);
+        assert_eq!(decap.inner, &inner_packet()[..]);
+    }
+
+    #[test]
+    fn vxlan_with_other_ethertype() {
+        let frame = ethernet(&[], 0x0806, &[0; 28]);
+        let mut vxlan = vec![0x08, 0, 0, 0, 0, 0, 1, 0];
+        vxlan.extend(frame);
+        let udp = build::udp(49152, VXLAN_PORT, &vxlan);
+        let packet = build::ipv4(ipproto::UDP, OUTER_SRC, OUTER_DST, &udp);
+
+        assert!(!Decapsulated::parse(&packet).unwrap().is_tunneled());
+    }
+
+    #[test]
+    fn geneve_with_options() {
+        let inner = build::ipv6(
+            ipproto::UDP,
+            [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+            &build::udp(1, 2, &[]),
+        );
+        // Two 4-byte units of options.
+        let mut geneve = vec![0x02, 0];
+        geneve.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        geneve.extend_from_slice(&[0xab, 0xcd, 0xef, 0]);
+        geneve.extend_from_slice(&[0x77; 8]);
+        geneve.extend(inner.iter());
+        let udp = build::udp(49152, GENEVE_PORT, &geneve);
+        let packet = build::ipv4(ipproto::UDP, OUTER_SRC, OUTER_DST, &udp);
+
+        let decap = Decapsulated::parse(&packet).unwrap();
+        assert_eq!(decap.layers[0].kind, TunnelKind::Geneve);
+        assert_eq!(decap.layers[0].id, Some(0xab_cdef));
+        assert_eq!(decap.layers[0].inner_offset, 20 + 8 + 16);
+        assert_eq!(decap.inner, &inner[..]);
+    }
+
+    #[test]
+    fn geneve_with_truncated_options() {
+        let mut geneve = vec![0x04, 0];
+        geneve.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        geneve.extend_from_slice(&[0; 12]);
+        let udp = build::udp(49152, GENEVE_PORT, &geneve);
+        let packet = build::ipv4(ipproto::UDP, OUTER_SRC, OUTER_DST, &udp);
+
+        assert_eq!(Decapsulated::parse(&packet), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn ip_in_ip() {
+        let packet = build::ipv4(ipproto::IPIP, OUTER_SRC, OUTER_DST, &inner_packet());
+
+        let decap = Decapsulated::parse(&packet).unwrap();
+        assert_eq!(decap.layers[0].kind, TunnelKind::IpInIp);
+        assert_eq!(decap.layers[0].id, None);
+        assert_eq!(decap.inner, &inner_packet()[..]);
+    }
+
+    #[test]
+    fn nested_tunnels_stop_at_max_depth() {
+        let mut packet = inner_packet();
+        for _ in 0..MAX_DEPTH + 2 {
+            packet = build::ipv4(ipproto::IPIP, OUTER_SRC, OUTER_DST, &packet);
+        }
+
+        let decap = Decapsulated::parse(&packet).unwrap();
+        assert_eq!(decap.layers.len(), MAX_DEPTH);
+        for (i, layer) in decap.layers.iter().enumerate() {
+            assert_eq!(layer.inner_offset, 20 * (i + 1));
+        }
+        // Two tunnels are left in the innermost packet.
+        assert_eq!(decap.inner.len(), inner_packet().len() + 40);
+        assert_eq!(decap.inner_flow().unwrap().protocol, ipproto::IPIP);
+    }
+}
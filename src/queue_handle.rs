@@ -1,95 +1,279 @@
 use libc::{c_int, c_void};
 use nflog_sys::*;
 use std::io;
-use std::mem::size_of;
-use std::os::unix::prelude::RawFd;
+use std::mem::{size_of, ManuallyDrop};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::ptr::NonNull;
 
 use super::{AddressFamily, CopyMode, Flags};
 
-pub(crate) struct QueueHandle {
+/// State of a [QueueHandle](QueueHandle).
+pub(crate) trait HandleState {
+    /// Release the resources of the state before the handle is closed.
+    fn release(&mut self) -> io::Result<()>;
+}
+
+/// The handle is open, address families can be bound.
+pub(crate) struct Opened;
+
+impl HandleState for Opened {
+    fn release(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The handle is bound to a group, which can be configured.
+pub(crate) struct Bound {
+    group_handle: NonNull<nflog_g_handle>,
+}
+
+impl HandleState for Bound {
+    fn release(&mut self) -> io::Result<()> {
+        traced!(
+            wrap_io_result!(nflog_unbind_group(self.group_handle.as_ptr())),
+            "unbind group"
+        )
+    }
+}
+
+pub(crate) struct QueueHandle<S: HandleState> {
     handle: NonNull<nflog_handle>,
-    group_handle: Option<NonNull<nflog_g_handle>>,
+    state: S,
+    /// Parent of the events of the queue, with the group as a field once it
+    /// is bound.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
-impl QueueHandle {
+impl QueueHandle<Opened> {
     pub(crate) fn open() -> io::Result<Self> {
         let handle = unsafe { nflog_open() };
-        if handle.is_null() {
-            return Err(io::Error::last_os_error());
-        }
+        let handle = trace_err!(
+            NonNull::new(handle).ok_or_else(io::Error::last_os_error),
+            "open"
+        )?;
 
-        Ok(QueueHandle {
-            handle: unsafe { NonNull::new_unchecked(handle) },
-            group_handle: None,
-        })
-    }
+        let handle = QueueHandle {
+            handle,
+            state: Opened,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("nflog", group = tracing::field::Empty),
+        };
+        trace!(debug, parent: handle.span(), "open succeeded");
 
-    pub(crate) fn as_ptr(&self) -> *mut nflog_handle {
-        self.handle.as_ptr()
+        Ok(handle)
     }
 
     pub(crate) fn bind(&self, address_family: AddressFamily) -> io::Result<()> {
-        wrap_io_result!(nflog_bind_pf(self.handle.as_ptr(), address_family as u16))
+        enter_span!(self);
+        traced!(
+            wrap_io_result!(nflog_bind_pf(self.handle.as_ptr(), address_family as u16)),
+            "bind",
+            family = ?address_family
+        )
     }
 
     pub(crate) fn unbind(&self, address_family: AddressFamily) -> io::Result<()> {
-        wrap_io_result!(nflog_unbind_pf(self.handle.as_ptr(), address_family as u16))
+        enter_span!(self);
+        traced!(
+            wrap_io_result!(nflog_unbind_pf(self.handle.as_ptr(), address_family as u16)),
+            "unbind",
+            family = ?address_family
+        )
     }
 
-    pub(crate) fn bind_group(&mut self, group_num: u16) -> io::Result<()> {
+    pub(crate) fn bind_group(self, group_num: u16) -> io::Result<QueueHandle<Bound>> {
+        enter_span!(self);
         let group_handle = unsafe { nflog_bind_group(self.handle.as_ptr(), group_num) };
-        if group_handle.is_null() {
-            return Err(io::Error::last_os_error());
-        }
+        let group_handle = traced!(
+            NonNull::new(group_handle).ok_or_else(io::Error::last_os_error),
+            "bind group",
+            group = group_num
+        )?;
 
-        let group_handle = unsafe { NonNull::new_unchecked(group_handle) };
-        self.group_handle = Some(group_handle);
+        #[cfg(feature = "tracing")]
+        self.span.record("group", group_num);
+        Ok(self.into_state(Bound { group_handle }))
+    }
+}
 
-        Ok(())
+impl QueueHandle<Bound> {
+    pub(crate) fn group_handle(&self) -> NonNull<nflog_g_handle> {
+        self.state.group_handle
+    }
+
+    pub(crate) fn set_mode(&mut self, mode: CopyMode, range: u32) -> io::Result<()> {
+        let ghandle = self.group_handle();
+
+        enter_span!(self);
+        traced!(
+            wrap_io_result!(nflog_set_mode(ghandle.as_ptr(), mode as u8, range)),
+            "set mode",
+            mode = mode as u8,
+            range
+        )
+    }
+
+    pub(crate) fn set_flags(&mut self, flags: Flags) -> io::Result<()> {
+        let ghandle = self.group_handle();
+
+        enter_span!(self);
+        traced!(
+            wrap_io_result!(nflog_set_flags(ghandle.as_ptr(), flags.bits())),
+            "set flags",
+            flags = ?flags
+        )
+    }
+}
+
+impl<S: HandleState> QueueHandle<S> {
+    pub(crate) fn as_ptr(&self) -> *mut nflog_handle {
+        self.handle.as_ptr()
     }
 
     pub(crate) fn fd(&self) -> RawFd {
         unsafe { nflog_fd(self.handle.as_ptr()) }
     }
 
-    pub(crate) fn group_handle(&self) -> io::Result<NonNull<nflog_g_handle>> {
-        self.group_handle
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "group handle is not initialized"))
+    /// Get the fd for registering it with a reactor, without giving up its
+    /// ownership.
+    pub(crate) fn nflog_fd(&self) -> NflogFd {
+        NflogFd(self.fd())
     }
 
-    pub(crate) fn set_mode(&mut self, mode: CopyMode, range: u32) -> io::Result<()> {
-        let ghandle = self.group_handle()?;
+    pub(crate) fn set_no_enobufs(&mut self, no_enobufs: bool) -> io::Result<()> {
+        let option_value: c_int = no_enobufs as c_int;
+        enter_span!(self);
+        traced!(
+            wrap_io_result!(libc::setsockopt(
+                self.fd(),
+                libc::SOL_NETLINK,
+                libc::NETLINK_NO_ENOBUFS,
+                (&option_value as *const c_int) as *const c_void,
+                size_of::<c_int>() as u32,
+            )),
+            "set no_enobufs",
+            no_enobufs
+        )
+    }
 
-        wrap_io_result!(nflog_set_mode(ghandle.as_ptr(), mode as u8, range))
+    /// Span of the events of the queue.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
     }
 
-    pub(crate) fn set_flags(&mut self, flags: Flags) -> io::Result<()> {
-        let ghandle = self.group_handle()?;
+    /// Release the state and close the handle.
+    ///
+    /// Unlike dropping the handle, errors are returned. The handle is closed
+    /// even if releasing the state fails, the first error is returned.
+    pub(crate) fn close(self) -> io::Result<()> {
+        let mut this = ManuallyDrop::new(self);
+        let result = this.teardown();
+        #[cfg(feature = "tracing")]
+        unsafe {
+            std::ptr::drop_in_place(&mut this.span)
+        };
 
-        wrap_io_result!(nflog_set_flags(ghandle.as_ptr(), flags.bits()))
+        result
     }
 
-    pub(crate) fn set_no_enobufs(&mut self, no_enobufs: bool) -> io::Result<()> {
-        let option_value: c_int = no_enobufs as c_int;
-        wrap_io_result!(libc::setsockopt(
-            self.fd(),
-            libc::SOL_NETLINK,
-            libc::NETLINK_NO_ENOBUFS,
-            (&option_value as *const c_int) as *const c_void,
-            size_of::<c_int>() as u32,
-        ))
+    fn teardown(&mut self) -> io::Result<()> {
+        enter_span!(self);
+        let released = self.state.release();
+        let closed = traced!(wrap_io_result!(nflog_close(self.handle.as_ptr())), "close");
+
+        released.and(closed)
+    }
+
+    /// Move the handle to the next state without releasing the current one.
+    fn into_state<T: HandleState>(self, state: T) -> QueueHandle<T> {
+        let this = ManuallyDrop::new(self);
+
+        QueueHandle {
+            handle: this.handle,
+            state,
+            #[cfg(feature = "tracing")]
+            span: unsafe { std::ptr::read(&this.span) },
+        }
+    }
+}
+
+impl<S: HandleState> AsRawFd for QueueHandle<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd()
+    }
+}
+
+impl<S: HandleState> AsFd for QueueHandle<S> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is open until `nflog_close` in drop.
+        unsafe { BorrowedFd::borrow_raw(self.fd()) }
     }
 }
 
-impl Drop for QueueHandle {
+/// The fd of a [QueueHandle](QueueHandle), for registering it with a reactor.
+///
+/// It doesn't own the fd, it is only closed by the handle. So it must be
+/// dropped before the handle.
+pub(crate) struct NflogFd(RawFd);
+
+impl AsRawFd for NflogFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl<S: HandleState> Drop for QueueHandle<S> {
     fn drop(&mut self) {
-        if let Some(group_handle) = self.group_handle.take() {
-            println!("Drop group handle");
-            unsafe { nflog_unbind_group(group_handle.as_ptr()) };
+        // Drop can't return failures, they are only traced if the `tracing`
+        // feature is enabled. `close` is used where they must be returned.
+        let _ = self.teardown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use tokio::io::unix::AsyncFd;
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+    }
+
+    fn assert_connected(pair: &(UnixDatagram, UnixDatagram)) {
+        pair.0.send(b"ping").unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(pair.1.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn closing_handles_leaves_other_fds_open() {
+        let kept = UnixDatagram::pair().unwrap();
+        // Opened after each handle is closed, so they are likely to reuse
+        // its fd, which a second close would take from them.
+        let mut reused = Vec::new();
+
+        for _ in 0..64 {
+            let handle = QueueHandle::open().unwrap();
+            let fd = handle.fd();
+            // Registered like in `QueueSocket::new` and dropped in the order
+            // of its fields: the fd leaves the reactor while it is still
+            // open and only the handle closes it.
+            let socket = AsyncFd::new(handle.nflog_fd()).unwrap();
+
+            drop(socket);
+            assert!(is_open(fd));
+            drop(handle);
+
+            reused.push(UnixDatagram::pair().unwrap());
+            assert_connected(&kept);
         }
 
-        println!("Drop handle");
-        unsafe { nflog_close(self.handle.as_ptr()) };
+        for pair in &reused {
+            assert_connected(pair);
+        }
     }
 }
@@ -0,0 +1,83 @@
+use futures::{future, ready};
+use std::future::Future;
+use std::io;
+use std::task::{Context, Poll};
+
+use super::{MessageHandler, Queue};
+
+/// Receive path shared by [QueueSocket](crate::QueueSocket) and the
+/// `async-io` socket, which only differ in how they read the socket.
+pub(crate) trait RecvSocket: Sized {
+    type Handler: MessageHandler;
+
+    fn queue(&mut self) -> &mut Queue<Self::Handler>;
+
+    /// Read the socket once and handle the datagrams that were read.
+    ///
+    /// Returns the number of datagrams read.
+    fn poll_recv_batch(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>>;
+
+    /// Remove the fd from the reactor and return the queue, which still
+    /// owns it.
+    fn into_queue(self) -> Queue<Self::Handler>;
+}
+
+/// Read batches until the socket would block or the receive budget is used
+/// up.
+pub(crate) fn poll_recv<S: RecvSocket>(
+    socket: &mut S,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    enter_span!(socket.queue().handle);
+    let mut received = ready!(socket.poll_recv_batch(cx))?;
+
+    while received < socket.queue().config.recv_budget {
+        match socket.poll_recv_batch(cx) {
+            Poll::Ready(result) => received += result?,
+            Poll::Pending => break,
+        }
+    }
+
+    Poll::Ready(Ok(()))
+}
+
+pub(crate) async fn listen_until<S, F>(
+    mut socket: S,
+    shutdown: F,
+) -> Result<S::Handler, (S::Handler, io::Error)>
+where
+    S: RecvSocket,
+    F: Future<Output = ()>,
+{
+    if let Err(e) = recv_until(&mut socket, shutdown).await {
+        return Err((socket.into_queue().into_handler(), e));
+    }
+
+    let mut queue = socket.into_queue();
+    match trace_err!(queue.handler_mut().on_shutdown(), "shutdown") {
+        Ok(()) => queue.close(),
+        Err(e) => Err((queue.into_handler(), e)),
+    }
+}
+
+async fn recv_until<S, F>(socket: &mut S, shutdown: F) -> io::Result<()>
+where
+    S: RecvSocket,
+    F: Future<Output = ()>,
+{
+    futures::pin_mut!(shutdown);
+
+    loop {
+        let stop = future::poll_fn(|cx| {
+            if shutdown.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(true));
+            }
+            poll_recv(socket, cx).map_ok(|()| false)
+        })
+        .await?;
+
+        if stop {
+            return Ok(());
+        }
+    }
+}
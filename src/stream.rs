@@ -0,0 +1,227 @@
+use futures::{future, ready, Stream};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::socket::{self, RecvSocket};
+use super::{AsyncMessageHandler, ErrorPolicy, Message, MessageHandler, OwnedMessage, QueueSocket};
+
+/// Socket that yields owned messages instead of passing them to a handler.
+///
+/// The messages of the datagrams read by one
+/// [recv](QueueSocket::recv) are buffered and yielded one by one before the
+/// socket is read again.
+pub type QueueStream = QueueSocket<MessageBuffer>;
+
+/// Handler that buffers messages for [QueueStream](QueueStream).
+#[derive(Debug, Default)]
+pub struct MessageBuffer {
+    messages: VecDeque<OwnedMessage>,
+    // Number of buffered messages of each datagram.
+    datagrams: VecDeque<usize>,
+    // The datagram of the last popped message has more messages.
+    mid_datagram: bool,
+}
+
+impl MessageBuffer {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub(crate) fn front(&self) -> Option<&OwnedMessage> {
+        self.messages.front()
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<OwnedMessage> {
+        let msg = self.messages.pop_front()?;
+        self.mid_datagram = match self.datagrams.front_mut() {
+            Some(n) if *n > 1 => {
+                *n -= 1;
+                true
+            }
+            _ => {
+                self.datagrams.pop_front();
+                false
+            }
+        };
+        Some(msg)
+    }
+
+    fn push(&mut self, msg: OwnedMessage) {
+        self.messages.push_back(msg);
+        if let Some(n) = self.datagrams.back_mut() {
+            *n += 1;
+        }
+    }
+
+    /// Drop the remaining messages of the datagram of the last popped one.
+    pub(crate) fn skip_datagram(&mut self) {
+        if self.mid_datagram {
+            let n = self.datagrams.pop_front().unwrap_or_default();
+            self.messages.drain(..n);
+            self.mid_datagram = false;
+        }
+    }
+}
+
+impl MessageHandler for MessageBuffer {
+    fn handle(&mut self, msg: Message<'_>) -> io::Result<()> {
+        self.push(OwnedMessage::from(msg));
+        Ok(())
+    }
+
+    fn handle_batch_start(&mut self) -> io::Result<()> {
+        self.datagrams.push_back(0);
+        Ok(())
+    }
+
+    fn handle_batch_end(&mut self) -> io::Result<()> {
+        if self.datagrams.back() == Some(&0) {
+            self.datagrams.pop_back();
+        }
+        Ok(())
+    }
+}
+
+impl QueueStream {
+    pub(crate) fn poll_next_message(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<OwnedMessage>> {
+        poll_next_message(self, cx)
+    }
+
+    /// Receive datagrams and pass their messages to the handler one by one,
+    /// awaiting the handler future for each of them.
+    ///
+    /// Unlike [recv](QueueSocket::recv), the socket is read only once: one
+    /// datagram in [Recv](crate::RecvMode::Recv) mode, or the datagrams of a
+    /// single batch in the other modes.
+    ///
+    /// Handler errors are treated according to [ErrorPolicy](ErrorPolicy),
+    /// separately for each datagram. If the future is dropped, i.e. by
+    /// `select!`, the message being handled is kept with the ones not handled
+    /// yet and passed to the handler again by the next call.
+    pub async fn recv_async<A>(&mut self, handler: &mut A) -> io::Result<()>
+    where
+        A: AsyncMessageHandler,
+    {
+        recv_async(self, handler).await
+    }
+
+    pub async fn listen_async<A>(&mut self, handler: &mut A) -> io::Result<()>
+    where
+        A: AsyncMessageHandler,
+    {
+        loop {
+            self.recv_async(handler).await?;
+        }
+    }
+}
+
+pub(crate) fn poll_next_message<S>(
+    socket: &mut S,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<OwnedMessage>>
+where
+    S: RecvSocket<Handler = MessageBuffer>,
+{
+    loop {
+        if let Some(msg) = socket.queue().handler_mut().pop() {
+            return Poll::Ready(Ok(msg));
+        }
+
+        ready!(socket::poll_recv(socket, cx))?;
+    }
+}
+
+pub(crate) async fn recv_async<S, A>(socket: &mut S, handler: &mut A) -> io::Result<()>
+where
+    S: RecvSocket<Handler = MessageBuffer>,
+    A: AsyncMessageHandler,
+{
+    if socket.queue().handler_mut().is_empty() {
+        future::poll_fn(|cx| socket.poll_recv_batch(cx)).await?;
+    }
+
+    let mut result = Ok(());
+    // A message is only removed once its future completed, so that it is not
+    // lost if this future is dropped.
+    while let Some(msg) = socket.queue().handler_mut().front().cloned() {
+        let handled = handler.handle(msg).await;
+        let queue = socket.queue();
+        queue.handler_mut().pop();
+
+        if let Err(e) = handled {
+            if result.is_ok() {
+                result = Err(e);
+            }
+            if queue.config.error_policy == ErrorPolicy::Stop {
+                queue.handler_mut().skip_datagram();
+            }
+        }
+    }
+
+    result
+}
+
+impl Stream for QueueStream {
+    type Item = io::Result<OwnedMessage>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_message(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(datagrams: &[&[u32]]) -> MessageBuffer {
+        let mut buffer = MessageBuffer::default();
+        for marks in datagrams {
+            buffer.handle_batch_start().unwrap();
+            for &mark in marks.iter() {
+                buffer.push(OwnedMessage::with_nfmark(mark));
+            }
+            buffer.handle_batch_end().unwrap();
+        }
+        buffer
+    }
+
+    fn marks(buffer: &mut MessageBuffer) -> Vec<u32> {
+        std::iter::from_fn(|| buffer.pop())
+            .map(|msg| msg.nfmark())
+            .collect()
+    }
+
+    #[test]
+    fn skips_the_rest_of_the_datagram() {
+        let mut buffer = buffer(&[&[1, 2, 3], &[], &[4, 5]]);
+
+        assert_eq!(buffer.pop().unwrap().nfmark(), 1);
+        buffer.skip_datagram();
+        assert_eq!(marks(&mut buffer), [4, 5]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn skip_after_the_last_message_keeps_the_next_datagram() {
+        let mut buffer = buffer(&[&[1], &[2, 3]]);
+
+        assert_eq!(buffer.pop().unwrap().nfmark(), 1);
+        buffer.skip_datagram();
+        assert_eq!(buffer.pop().unwrap().nfmark(), 2);
+        buffer.skip_datagram();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn skip_before_pop_keeps_the_datagram() {
+        let mut buffer = buffer(&[&[6, 7]]);
+
+        buffer.skip_datagram();
+        assert_eq!(marks(&mut buffer), [6, 7]);
+    }
+}
@@ -0,0 +1,213 @@
+use futures::ready;
+use io_uring::{cqueue, opcode, squeue, types, IoUring, Probe};
+use std::os::unix::prelude::RawFd;
+use std::task::{Context, Poll};
+use std::{io, mem};
+use tokio::io::unix::AsyncFd;
+
+const BUFFER_GROUP: u16 = 0;
+
+const RECV: u64 = 0;
+const PROVIDE_BUFFERS: u64 = 1;
+
+/// Receive path that keeps multishot receives outstanding on the socket and
+/// lets the kernel pick buffers from a provided buffer group.
+pub(crate) struct UringRecv {
+    // Declared before `buffers`, so that the ring is closed and the
+    // receives are cancelled before the buffers are freed.
+    ring: AsyncFd<IoUring>,
+    buffers: Box<[u8]>,
+    buffer_size: usize,
+    fd: RawFd,
+    receives: usize,
+    armed: usize,
+    received: bool,
+    completions: Vec<(u64, i32, u32)>,
+    // Submissions since the last `take_syscalls`.
+    syscalls: u64,
+}
+
+impl UringRecv {
+    /// Returns an [Unsupported](io::ErrorKind::Unsupported) error if the
+    /// kernel does not support the required operations.
+    pub(crate) fn new(
+        fd: RawFd,
+        receives: usize,
+        buffers: u16,
+        buffer_size: usize,
+    ) -> io::Result<Self> {
+        let receives = receives.max(1);
+        let buffers = buffers.max(1);
+
+        // Each buffer may have to be provided again within one batch.
+        let entries = (buffers as u32 + receives as u32).next_power_of_two();
+        let ring = IoUring::new(entries)?;
+
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        if !probe.is_supported(opcode::RecvMulti::CODE)
+            || !probe.is_supported(opcode::ProvideBuffers::CODE)
+        {
+            return Err(unsupported());
+        }
+
+        let mut recv = Self {
+            ring: AsyncFd::new(ring)?,
+            buffers: vec![0; buffers as usize * buffer_size].into_boxed_slice(),
+            buffer_size,
+            fd,
+            receives,
+            armed: 0,
+            received: false,
+            completions: Vec::new(),
+            syscalls: 0,
+        };
+
+        let provide = opcode::ProvideBuffers::new(
+            recv.buffers.as_mut_ptr(),
+            buffer_size as i32,
+            buffers,
+            BUFFER_GROUP,
+            0,
+        );
+        recv.push(provide.build().user_data(PROVIDE_BUFFERS))?;
+        recv.arm()?;
+
+        Ok(recv)
+    }
+
+    /// Pass the received datagrams to `handle`.
+    ///
+    /// Returns the number of datagrams received. All of them are handled
+    /// before the first error is returned.
+    pub(crate) fn poll_recv<F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut handle: F,
+    ) -> Poll<io::Result<usize>>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<()>,
+    {
+        loop {
+            let n = self.complete(&mut handle)?;
+            if n > 0 {
+                return Poll::Ready(Ok(n));
+            }
+
+            // Completions posted after the readiness is cleared wake the
+            // task again, the ones posted before are picked up above.
+            let mut guard = ready!(self.ring.poll_read_ready(cx))?;
+            guard.clear_ready();
+        }
+    }
+
+    fn complete<F>(&mut self, handle: &mut F) -> io::Result<usize>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<()>,
+    {
+        let mut completions = mem::take(&mut self.completions);
+        completions.extend(
+            self.ring
+                .get_mut()
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags())),
+        );
+
+        let mut n = 0;
+        let mut result = Ok(());
+        for (user_data, res, flags) in completions.drain(..) {
+            if user_data == PROVIDE_BUFFERS {
+                if res < 0 && result.is_ok() {
+                    result = Err(io::Error::from_raw_os_error(-res));
+                }
+                continue;
+            }
+
+            if !cqueue::more(flags) {
+                self.armed -= 1;
+            }
+
+            if res < 0 {
+                match -res {
+                    // All buffers are in use, the receive is armed again below
+                    // once they are provided back.
+                    libc::ENOBUFS => {}
+                    // Kernels before 6.0 reject multishot receives.
+                    libc::EINVAL if !self.received => return Err(unsupported()),
+                    errno => {
+                        let e = io::Error::from_raw_os_error(errno);
+                        trace!(warn, error = %e, "recv failed");
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if let Some(bid) = cqueue::buffer_select(flags) {
+                self.received = true;
+                n += 1;
+
+                let start = bid as usize * self.buffer_size;
+                let handled = handle(&mut self.buffers[start..start + res as usize]);
+                if result.is_ok() {
+                    result = handled;
+                }
+
+                let provide = opcode::ProvideBuffers::new(
+                    self.buffers[start..].as_mut_ptr(),
+                    self.buffer_size as i32,
+                    1,
+                    BUFFER_GROUP,
+                    bid,
+                );
+                self.push(provide.build().user_data(PROVIDE_BUFFERS))?;
+            }
+        }
+        self.completions = completions;
+
+        self.arm()?;
+        result.map(|()| n)
+    }
+
+    /// Keep the configured number of receives outstanding and submit queued
+    /// entries.
+    fn arm(&mut self) -> io::Result<()> {
+        while self.armed < self.receives {
+            let recv = opcode::RecvMulti::new(types::Fd(self.fd), BUFFER_GROUP);
+            self.push(recv.build().user_data(RECV))?;
+            self.armed += 1;
+        }
+
+        self.submit()
+    }
+
+    /// Returns the number of `io_uring_enter` syscalls since the last call.
+    pub(crate) fn take_syscalls(&mut self) -> u64 {
+        mem::take(&mut self.syscalls)
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        self.syscalls += 1;
+        self.ring.get_ref().submit()?;
+        Ok(())
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            let pushed = unsafe { self.ring.get_mut().submission().push(&entry) };
+            match pushed {
+                Ok(()) => return Ok(()),
+                Err(_) => self.submit()?,
+            }
+        }
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "io_uring multishot receive is not supported",
+    )
+}
@@ -0,0 +1,428 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::{AddressFamily, Lagged, MacAddr, OwnedMessage};
+
+const FRAME_LEN: usize = 4;
+
+const KIND_MESSAGE: u8 = 0;
+const KIND_ERROR: u8 = 1;
+const KIND_LAGGED: u8 = 2;
+
+const HAS_ADDRESS_FAMILY: u16 = 1 << 0;
+const HAS_HWHDR: u16 = 1 << 1;
+const HAS_HWADDR: u16 = 1 << 2;
+const HAS_TIMESTAMP: u16 = 1 << 3;
+const HAS_PAYLOAD: u16 = 1 << 4;
+const HAS_UID: u16 = 1 << 5;
+const HAS_GID: u16 = 1 << 6;
+const HAS_LOCAL_SEQNUM: u16 = 1 << 7;
+const HAS_GLOBAL_SEQNUM: u16 = 1 << 8;
+
+// Large enough for any message of a 64 KiB netlink datagram.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+pub(crate) fn encode(item: &io::Result<OwnedMessage>, buf: &mut BytesMut) {
+    let start = buf.len();
+    buf.put_u32(0);
+
+    match item {
+        Ok(msg) => encode_message(msg, buf),
+        Err(e) => match Lagged::from_io_error(e) {
+            Some(Lagged(dropped)) => {
+                buf.put_u8(KIND_LAGGED);
+                buf.put_u64(dropped);
+            }
+            None => {
+                buf.put_u8(KIND_ERROR);
+                buf.put_i32(e.raw_os_error().unwrap_or(0));
+                put_bytes(buf, e.to_string().as_bytes());
+            }
+        },
+    }
+
+    let len = (buf.len() - start - FRAME_LEN) as u32;
+    buf[start..start + FRAME_LEN].copy_from_slice(&len.to_be_bytes());
+}
+
+fn encode_message(msg: &OwnedMessage, buf: &mut BytesMut) {
+    let mut present = 0;
+    for (flag, is_some) in [
+        (HAS_ADDRESS_FAMILY, msg.address_family().is_some()),
+        (HAS_HWHDR, msg.packet_hwhdr().is_some()),
+        (HAS_HWADDR, msg.packet_hwaddr().is_some()),
+        (HAS_TIMESTAMP, msg.timestamp().is_some()),
+        (HAS_PAYLOAD, msg.payload().is_some()),
+        (HAS_UID, msg.uid().is_some()),
+        (HAS_GID, msg.gid().is_some()),
+        (HAS_LOCAL_SEQNUM, msg.local_seqnum().is_some()),
+        (HAS_GLOBAL_SEQNUM, msg.global_seqnum().is_some()),
+    ] {
+        if is_some {
+            present |= flag;
+        }
+    }
+
+    buf.put_u8(KIND_MESSAGE);
+    buf.put_u16(present);
+
+    if let Some(address_family) = msg.address_family() {
+        buf.put_i32(address_family as i32);
+    }
+    buf.put_u16(msg.hwtype());
+    if let Some(hwhdr) = msg.packet_hwhdr() {
+        put_bytes(buf, hwhdr);
+    }
+    if let Some(hwaddr) = msg.packet_hwaddr() {
+        buf.put_slice(&hwaddr.octets());
+    }
+    buf.put_u16(msg.l3_proto());
+    buf.put_u32(msg.nfmark());
+    if let Some(timestamp) = msg.timestamp() {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        buf.put_u64(since_epoch.as_secs());
+        buf.put_u32(since_epoch.subsec_nanos());
+    }
+    buf.put_u32(msg.indev());
+    buf.put_u32(msg.physindev());
+    buf.put_u32(msg.outdev());
+    buf.put_u32(msg.physoutdev());
+    if let Some(payload) = msg.payload() {
+        put_bytes(buf, payload);
+    }
+    put_bytes(buf, msg.prefix().as_bytes());
+    for value in [
+        msg.uid(),
+        msg.gid(),
+        msg.local_seqnum(),
+        msg.global_seqnum(),
+    ]
+    .iter()
+    .flatten()
+    {
+        buf.put_u32(*value);
+    }
+}
+
+/// Split the next frame off `buf` and decode it.
+///
+/// Returns `None` if the frame is not complete yet.
+pub(crate) fn decode(buf: &mut BytesMut) -> io::Result<Option<io::Result<OwnedMessage>>> {
+    if buf.len() < FRAME_LEN {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid("frame is too long"));
+    }
+    if buf.len() < FRAME_LEN + len {
+        buf.reserve(FRAME_LEN + len - buf.len());
+        return Ok(None);
+    }
+
+    buf.advance(FRAME_LEN);
+    let mut frame = buf.split_to(len).freeze();
+
+    match get_u8(&mut frame)? {
+        KIND_MESSAGE => decode_message(&mut frame).map(|msg| Some(Ok(msg))),
+        KIND_ERROR => {
+            let code = get_i32(&mut frame)?;
+            let description = get_string(&mut frame)?;
+            let e = match code {
+                0 => io::Error::other(description),
+                code => io::Error::from_raw_os_error(code),
+            };
+            Ok(Some(Err(e)))
+        }
+        KIND_LAGGED => {
+            let dropped = get_u64(&mut frame)?;
+            Ok(Some(Err(io::Error::other(Lagged(dropped)))))
+        }
+        _ => Err(invalid("unknown frame kind")),
+    }
+}
+
+fn decode_message(frame: &mut Bytes) -> io::Result<OwnedMessage> {
+    let present = get_u16(frame)?;
+    let has = |flag| present & flag != 0;
+
+    let address_family = if has(HAS_ADDRESS_FAMILY) {
+        Some(
+            AddressFamily::from_i32(get_i32(frame)?)
+                .ok_or_else(|| invalid("unknown address family"))?,
+        )
+    } else {
+        None
+    };
+    let hwtype = get_u16(frame)?;
+    let packet_hwhdr = if has(HAS_HWHDR) {
+        Some(get_bytes(frame)?)
+    } else {
+        None
+    };
+    let packet_hwaddr = if has(HAS_HWADDR) {
+        let octets = get_slice(frame, 6)?;
+        Some(MacAddr::new(
+            octets[0], octets[1], octets[2], octets[3], octets[4], octets[5],
+        ))
+    } else {
+        None
+    };
+    let l3_proto = get_u16(frame)?;
+    let nfmark = get_u32(frame)?;
+    let timestamp = if has(HAS_TIMESTAMP) {
+        let secs = get_u64(frame)?;
+        let nanos = get_u32(frame)?;
+        if nanos >= 1_000_000_000 {
+            return Err(invalid("timestamp nanoseconds out of range"));
+        }
+        Some(
+            UNIX_EPOCH
+                .checked_add(Duration::new(secs, nanos))
+                .ok_or_else(|| invalid("timestamp out of range"))?,
+        )
+    } else {
+        None
+    };
+    let indev = get_u32(frame)?;
+    let physindev = get_u32(frame)?;
+    let outdev = get_u32(frame)?;
+    let physoutdev = get_u32(frame)?;
+    let payload = if has(HAS_PAYLOAD) {
+        Some(get_bytes(frame)?)
+    } else {
+        None
+    };
+    let prefix = get_string(frame)?;
+    let mut get_opt_u32 = |flag| has(flag).then(|| get_u32(frame)).transpose();
+    let uid = get_opt_u32(HAS_UID)?;
+    let gid = get_opt_u32(HAS_GID)?;
+    let local_seqnum = get_opt_u32(HAS_LOCAL_SEQNUM)?;
+    let global_seqnum = get_opt_u32(HAS_GLOBAL_SEQNUM)?;
+
+    Ok(OwnedMessage {
+        address_family,
+        hwtype,
+        packet_hwhdr,
+        packet_hwaddr,
+        l3_proto,
+        nfmark,
+        timestamp,
+        indev,
+        physindev,
+        outdev,
+        physoutdev,
+        payload,
+        prefix,
+        uid,
+        gid,
+        local_seqnum,
+        global_seqnum,
+    })
+}
+
+fn put_bytes(buf: &mut BytesMut, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn get_slice(frame: &mut Bytes, len: usize) -> io::Result<Bytes> {
+    if frame.len() < len {
+        return Err(invalid("frame is truncated"));
+    }
+    Ok(frame.split_to(len))
+}
+
+fn get_u8(frame: &mut Bytes) -> io::Result<u8> {
+    Ok(get_slice(frame, 1)?[0])
+}
+
+fn get_u16(frame: &mut Bytes) -> io::Result<u16> {
+    Ok(get_slice(frame, 2)?.get_u16())
+}
+
+fn get_u32(frame: &mut Bytes) -> io::Result<u32> {
+    Ok(get_slice(frame, 4)?.get_u32())
+}
+
+fn get_i32(frame: &mut Bytes) -> io::Result<i32> {
+    Ok(get_slice(frame, 4)?.get_i32())
+}
+
+fn get_u64(frame: &mut Bytes) -> io::Result<u64> {
+    Ok(get_slice(frame, 8)?.get_u64())
+}
+
+fn get_bytes(frame: &mut Bytes) -> io::Result<Bytes> {
+    let len = get_u32(frame)? as usize;
+    get_slice(frame, len)
+}
+
+fn get_string(frame: &mut Bytes) -> io::Result<String> {
+    let bytes = get_bytes(frame)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(item: &io::Result<OwnedMessage>) -> io::Result<OwnedMessage> {
+        let mut buf = BytesMut::new();
+        encode(item, &mut buf);
+        let decoded = decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        decoded
+    }
+
+    fn full_message() -> OwnedMessage {
+        OwnedMessage {
+            address_family: Some(AddressFamily::Inet6),
+            hwtype: 1,
+            packet_hwhdr: Some(Bytes::from_static(&[0xaa; 14])),
+            packet_hwaddr: Some(MacAddr::new(1, 2, 3, 4, 5, 6)),
+            l3_proto: 0x86dd,
+            nfmark: 7,
+            timestamp: Some(UNIX_EPOCH + Duration::new(1_600_000_000, 999_999_999)),
+            indev: 1,
+            physindev: 2,
+            outdev: 3,
+            physoutdev: 4,
+            payload: Some(Bytes::from_static(b"payload")),
+            prefix: "prefix: ".to_string(),
+            uid: Some(1000),
+            gid: Some(100),
+            local_seqnum: Some(5),
+            global_seqnum: Some(6),
+        }
+    }
+
+    // Encoded timestamp of `full_message`: after the kind, presence bits,
+    // address family, hwtype, hwhdr, hwaddr, l3_proto and nfmark.
+    const TIMESTAMP_OFFSET: usize = FRAME_LEN + 1 + 2 + 4 + 2 + (4 + 14) + 6 + 2 + 4;
+
+    #[test]
+    fn message_with_every_field() {
+        let msg = full_message();
+        assert_eq!(round_trip(&Ok(msg.clone())).unwrap(), msg);
+    }
+
+    #[test]
+    fn message_without_optional_fields() {
+        let msg = OwnedMessage::with_nfmark(7);
+        assert_eq!(round_trip(&Ok(msg.clone())).unwrap(), msg);
+    }
+
+    #[test]
+    fn message_with_each_optional_field() {
+        let full = full_message();
+        let fields: [fn(&mut OwnedMessage, &OwnedMessage); 9] = [
+            |m, f| m.address_family = f.address_family,
+            |m, f| m.packet_hwhdr = f.packet_hwhdr.clone(),
+            |m, f| m.packet_hwaddr = f.packet_hwaddr,
+            |m, f| m.timestamp = f.timestamp,
+            |m, f| m.payload = f.payload.clone(),
+            |m, f| m.uid = f.uid,
+            |m, f| m.gid = f.gid,
+            |m, f| m.local_seqnum = f.local_seqnum,
+            |m, f| m.global_seqnum = f.global_seqnum,
+        ];
+
+        for set in fields.iter() {
+            let mut msg = OwnedMessage::with_nfmark(7);
+            set(&mut msg, &full);
+            assert_eq!(round_trip(&Ok(msg.clone())).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn os_error() {
+        let e = round_trip(&Err(io::Error::from_raw_os_error(libc::ENOBUFS))).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOBUFS));
+    }
+
+    #[test]
+    fn other_error_keeps_description() {
+        let e = round_trip(&Err(io::Error::other("handler failed"))).unwrap_err();
+        assert_eq!(e.raw_os_error(), None);
+        assert_eq!(e.to_string(), "handler failed");
+    }
+
+    #[test]
+    fn lag_notice() {
+        let e = round_trip(&Err(io::Error::other(Lagged(3)))).unwrap_err();
+        assert_eq!(Lagged::from_io_error(&e), Some(Lagged(3)));
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let mut encoded = BytesMut::new();
+        encode(&Ok(full_message()), &mut encoded);
+        encode(&Ok(OwnedMessage::with_nfmark(8)), &mut encoded);
+
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            buf.put_u8(*byte);
+            while let Some(item) = decode(&mut buf).unwrap() {
+                decoded.push(item.unwrap());
+            }
+        }
+
+        assert_eq!(decoded, vec![full_message(), OwnedMessage::with_nfmark(8)]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn frame_length_limit() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME_LEN as u32);
+        assert!(decode(&mut buf).unwrap().is_none());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME_LEN as u32 + 1);
+        let e = decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut buf = BytesMut::new();
+        encode(&Ok(full_message()), &mut buf);
+        let len = buf.len() - FRAME_LEN - 1;
+        buf.truncate(buf.len() - 1);
+        buf[..FRAME_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+
+        let e = decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_frame_kind() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(1);
+        buf.put_u8(3);
+        let e = decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        for (secs, nanos) in [(0, 1_000_000_000), (u64::MAX, 0)].iter() {
+            let mut buf = BytesMut::new();
+            encode(&Ok(full_message()), &mut buf);
+            let mut timestamp = &mut buf[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 12];
+            assert_eq!(timestamp[..8], 1_600_000_000u64.to_be_bytes());
+            timestamp.put_u64(*secs);
+            timestamp.put_u32(*nanos);
+
+            let e = decode(&mut buf).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}